@@ -1,29 +1,54 @@
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::time::Duration;
+use chrono::DateTime;
 use chrono::NaiveDateTime;
 use chrono::Utc;
 
+use crate::json::IntOrFloat;
 use crate::json::Value;
+use crate::ulid;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Alarm {
     pub when: NaiveDateTime,
     pub what: String,
     pub channel_id: String,
-    pub message_id: String
+    pub message_id: String,
+    // the user who set the alarm.
+    pub author: Option<String>,
+    pub created: Option<NaiveDateTime>,
+    // how often the alarm should repeat, and which channel it should be posted in
+    // if not the one it was set in.  nothing sets or acts on these yet,
+    // they're only kept so saved alarms have room for them.
+    pub recurrence: Option<Duration>,
+    pub target: Option<String>,
+    // how many times posting this alarm has failed.
+    pub attempts: u32,
+    // whether it's been reacted to for going off (or tried and failed to be),
+    // so trying to post it again doesn't react again.
+    pub reacted: bool,
+    // if set, posting failed and is retried at this time instead of at when.
+    pub retry_at: Option<NaiveDateTime>
 }
 
+// the version written into saved alarms by to_json.
+// bump this whenever the saved format changes,
+// and teach from_json how to read the old version.
+// version 2 added attempts and retry_at.
+// version 3 added reacted.
+pub const FORMAT_VERSION: i64 = 3;
+
 impl Alarm {
-    fn parse_timer(mut text: String) -> Option<(NaiveDateTime, String)> {
+    // the duration is counted from sent, so a message that's handled late
+    // (like when catching up after being offline) still goes off when it was meant to.
+    fn parse_timer(mut timer: String, sent: NaiveDateTime) -> Option<(NaiveDateTime, String)> {
         // messages are formatted like this:
         // "@bot_handle in 2d12h5m30s wish mom a happy birthday"
-        // everything before "in " isn't parsed,
-        // and then the first word after "in " is converted to a duration,
-        // and then the rest of the message the text to say when the alarm goes off.
-        let mut timer = text.split_off(text.find("in ").map(|index| index + "in ".len())?);
+        // and this gets what's after "in " (the command router takes care of that).
+        // the first word is converted to a duration,
+        // and then the rest of it is the text to say when the alarm goes off.
         let message = timer.split_off(timer.find(" ").map(|index| index + " ".len())?);
-        let now = Utc::now().naive_utc();
         let duration = {
             const ONE_SECOND: u64 = 1;
             const ONE_MINUTE: u64 = ONE_SECOND * 60;
@@ -70,29 +95,153 @@ impl Alarm {
             Duration::from_secs(days + hours + mins + secs)
         };
 
-        Some((now + duration, message))
+        Some((sent + duration, message))
     }
 
-    pub fn from_message(message: &HashMap<String, Value>) -> Option<Self> {
+    // timer is the message with everything up to and including "in " taken off.
+    pub fn from_message(message: &HashMap<String, Value>, timer: &str) -> Option<Self> {
         let Some(Value::String(channel_id)) = message.get("channel") else {
             return None;
         };
         let Some(Value::String(message_id)) = message.get("_id") else {
             return None;
         };
-        let Some(Value::String(message_text)) = message.get("content") else {
-            return None;
-        };
-        let (when, what) = Self::parse_timer(message_text.to_string())?;
+        // message ids have the time they were sent in them.
+        let sent = ulid::timestamp(message_id).unwrap_or_else(|| Utc::now().naive_utc());
+        let (when, what) = Self::parse_timer(timer.to_string(), sent)?;
 
         let channel_id = channel_id.to_string();
         let message_id = message_id.to_string();
+        let author = match message.get("author") {
+            Some(Value::String(author)) => Some(author.to_string()),
+            _ => None
+        };
 
         Some(Self {
             when,
             what,
             channel_id,
-            message_id
+            message_id,
+            author,
+            created: Some(sent),
+            recurrence: None,
+            target: None,
+            attempts: 0,
+            reacted: false,
+            retry_at: None
+        })
+    }
+
+    // when the alarm should next be posted.
+    pub fn due(&self) -> NaiveDateTime {
+        self.retry_at.unwrap_or(self.when)
+    }
+
+    pub fn to_json(&self) -> Value {
+        fn millis(datetime: &NaiveDateTime) -> Value {
+            Value::Number(IntOrFloat::Int(datetime.and_utc().timestamp_millis()))
+        }
+        fn string_or_null(string: &Option<String>) -> Value {
+            match string {
+                Some(string) => Value::String(string.to_string()),
+                None => Value::Null
+            }
+        }
+
+        Value::Object(HashMap::from([
+            ("version".to_string(), Value::Number(IntOrFloat::Int(FORMAT_VERSION))),
+            ("when".to_string(), millis(&self.when)),
+            ("what".to_string(), Value::String(self.what.to_string())),
+            ("channel".to_string(), Value::String(self.channel_id.to_string())),
+            ("message".to_string(), Value::String(self.message_id.to_string())),
+            ("author".to_string(), string_or_null(&self.author)),
+            ("created".to_string(), match &self.created {
+                Some(created) => millis(created),
+                None => Value::Null
+            }),
+            ("recurrence".to_string(), match &self.recurrence {
+                Some(recurrence) => Value::Number(IntOrFloat::Int(recurrence.as_secs() as i64)),
+                None => Value::Null
+            }),
+            ("target".to_string(), string_or_null(&self.target)),
+            ("attempts".to_string(), Value::Number(IntOrFloat::Int(self.attempts as i64))),
+            ("reacted".to_string(), Value::Boolean(self.reacted)),
+            ("retry_at".to_string(), match &self.retry_at {
+                Some(retry_at) => millis(retry_at),
+                None => Value::Null
+            })
+        ]))
+    }
+
+    pub fn from_json(saved: &HashMap<String, Value>) -> Result<Self, String> {
+        fn datetime(millis: &IntOrFloat) -> Option<NaiveDateTime> {
+            DateTime::from_timestamp_millis(millis.as_int()).map(|datetime| datetime.naive_utc())
+        }
+
+        let Some(Value::Number(version)) = saved.get("version") else {
+            return Err("saved alarm has no version".to_string());
+        };
+        if version.as_int() < 1 || version.as_int() > FORMAT_VERSION {
+            return Err(format!("saved alarm has unknown version {}", version.as_int()));
+        }
+        let Some(Value::Number(when)) = saved.get("when") else {
+            return Err("saved alarm has no time".to_string());
+        };
+        let Some(when) = datetime(when) else {
+            return Err("saved alarm has an invalid time".to_string());
+        };
+        let Some(Value::String(what)) = saved.get("what") else {
+            return Err("saved alarm has no message".to_string());
+        };
+        let Some(Value::String(channel_id)) = saved.get("channel") else {
+            return Err("saved alarm has no channel".to_string());
+        };
+        let Some(Value::String(message_id)) = saved.get("message") else {
+            return Err("saved alarm has no message id".to_string());
+        };
+        let author = match saved.get("author") {
+            Some(Value::String(author)) => Some(author.to_string()),
+            _ => None
+        };
+        let created = match saved.get("created") {
+            Some(Value::Number(created)) => datetime(created),
+            _ => None
+        };
+        let recurrence = match saved.get("recurrence") {
+            Some(Value::Number(seconds)) if seconds.as_int() > 0 => Some(Duration::from_secs(seconds.as_int() as u64)),
+            _ => None
+        };
+        let target = match saved.get("target") {
+            Some(Value::String(target)) => Some(target.to_string()),
+            _ => None
+        };
+        // version 1 doesn't have these, so they're left at their defaults.
+        let attempts = match saved.get("attempts") {
+            Some(Value::Number(attempts)) => u32::try_from(attempts.as_int()).unwrap_or(0),
+            _ => 0
+        };
+        let retry_at = match saved.get("retry_at") {
+            Some(Value::Number(retry_at)) => datetime(retry_at),
+            _ => None
+        };
+        // before version 3, it was reacted to before the first attempt to post it.
+        let reacted = match saved.get("reacted") {
+            Some(Value::Boolean(reacted)) => *reacted,
+            _ => attempts > 0
+        };
+
+        Ok(Self {
+            when,
+            what: what.to_string(),
+            channel_id: channel_id.to_string(),
+            message_id: message_id.to_string(),
+            author,
+            created,
+            recurrence,
+            target,
+            attempts,
+            reacted,
+            retry_at
         })
     }
 }
@@ -105,7 +254,7 @@ impl PartialOrd for Alarm {
 
 impl PartialEq for Alarm {
     fn eq(&self, other: &Self) -> bool {
-        NaiveDateTime::eq(&self.when, &other.when)
+        NaiveDateTime::eq(&self.due(), &other.due())
     }
 }
 
@@ -115,6 +264,85 @@ impl Ord for Alarm {
     fn cmp(&self, other: &Self) -> Ordering {
         // they're swapped because sooner alarms
         // should be higher priority than later ones.
-        NaiveDateTime::cmp(&other.when, &self.when)
+        NaiveDateTime::cmp(&other.due(), &self.due())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    fn at(millis: i64) -> NaiveDateTime {
+        DateTime::from_timestamp_millis(millis).unwrap().naive_utc()
+    }
+
+    fn round_trip(alarm: &Alarm) -> Alarm {
+        let Value::Object(saved) = json::parse_value(json::stringify(&alarm.to_json()).as_bytes(), 0).unwrap().0 else {
+            panic!("to_json didn't make an object");
+        };
+        Alarm::from_json(&saved).unwrap()
+    }
+
+    fn saved(text: &str) -> HashMap<String, Value> {
+        let Value::Object(saved) = json::parse_value(text.as_bytes(), 0).unwrap().0 else {
+            panic!("{} isn't an object", text);
+        };
+        saved
+    }
+
+    #[test]
+    fn round_trips() {
+        let everything = Alarm {
+            when: at(1_800_000_000_123),
+            what: "tabs\tnewlines\n and \"quotes\"".to_string(),
+            channel_id: "01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string(),
+            message_id: "01BX5ZZKBKACTAV9WEVGEMMVRZ".to_string(),
+            author: Some("01BX5ZZKBKACTAV9WEVGEMMVR0".to_string()),
+            created: Some(at(1_700_000_000_456)),
+            recurrence: Some(Duration::from_secs(60 * 60 * 24)),
+            target: Some("01BX5ZZKBKACTAV9WEVGEMMVR1".to_string()),
+            attempts: 3,
+            reacted: true,
+            retry_at: Some(at(1_800_000_060_000))
+        };
+        assert_eq!(format!("{:?}", round_trip(&everything)), format!("{:?}", everything));
+
+        let nothing_optional = Alarm {
+            author: None,
+            created: None,
+            recurrence: None,
+            target: None,
+            attempts: 0,
+            reacted: false,
+            retry_at: None,
+            ..everything
+        };
+        assert_eq!(format!("{:?}", round_trip(&nothing_optional)), format!("{:?}", nothing_optional));
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let alarm = Alarm::from_json(&saved(r#"{"version":1,"when":1800000000000,"what":"hi","channel":"01ARZ3NDEKTSV4RRFFQ69G5FAV","message":"01BX5ZZKBKACTAV9WEVGEMMVRZ","author":null,"created":null,"recurrence":null,"target":null}"#)).unwrap();
+        assert_eq!(alarm.when, at(1_800_000_000_000));
+        assert_eq!(alarm.what, "hi");
+        assert_eq!(alarm.attempts, 0);
+        assert_eq!(alarm.retry_at, None);
+        assert!(!alarm.reacted);
+    }
+
+    #[test]
+    fn reads_version_2() {
+        let version_2 = |attempts: u32| Alarm::from_json(&saved(&format!(r#"{{"version":2,"when":1800000000000,"what":"hi","channel":"01ARZ3NDEKTSV4RRFFQ69G5FAV","message":"01BX5ZZKBKACTAV9WEVGEMMVRZ","author":null,"created":null,"recurrence":null,"target":null,"attempts":{},"retry_at":null}}"#, attempts))).unwrap();
+        assert!(!version_2(0).reacted);
+        assert!(version_2(2).reacted);
+    }
+
+    #[test]
+    fn rejects_what_it_cant_read() {
+        assert_eq!(Alarm::from_json(&saved(r#"{"when":0,"what":"hi","channel":"a","message":"b"}"#)).unwrap_err(), "saved alarm has no version");
+        assert_eq!(Alarm::from_json(&saved(r#"{"version":4,"when":0,"what":"hi","channel":"a","message":"b"}"#)).unwrap_err(), "saved alarm has unknown version 4");
+        assert_eq!(Alarm::from_json(&saved(r#"{"version":2,"what":"hi","channel":"a","message":"b"}"#)).unwrap_err(), "saved alarm has no time");
+        assert_eq!(Alarm::from_json(&saved(r#"{"version":2,"when":0,"what":"hi","message":"b"}"#)).unwrap_err(), "saved alarm has no channel");
     }
 }
@@ -14,8 +14,23 @@ impl AlarmHeap {
         self.0.push(alarm);
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &Alarm> {
+        self.0.iter()
+    }
+
+    pub fn contains(&self, message_id: &str) -> bool {
+        self.0.iter().any(|alarm| alarm.message_id == message_id)
+    }
+
+    // takes the alarm set by the given message out of the heap, if it's in there.
+    pub fn remove(&mut self, message_id: &str) -> Option<Alarm> {
+        let removed = self.0.iter().find(|alarm| alarm.message_id == message_id).cloned()?;
+        self.0.retain(|alarm| alarm.message_id != message_id);
+        Some(removed)
+    }
+
     pub fn pop_if_timeup(&mut self, now: &NaiveDateTime) -> Option<Alarm> {
-        if self.0.peek().is_some_and(|next_alarm| &next_alarm.when <= now) {
+        if self.0.peek().is_some_and(|next_alarm| next_alarm.due() <= *now) {
             self.0.pop()
         } else {
             None
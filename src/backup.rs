@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::alarm::Alarm;
+use crate::alarm_heap::AlarmHeap;
+use crate::json;
+use crate::json::IntOrFloat;
+use crate::json::Value;
+use crate::store;
+
+// exported alarms are one json document:
+// {"version":1,"alarms":[<each alarm, as made by Alarm::to_json>]}
+// the version is the version of this wrapper,
+// each alarm carries its own version as well.
+
+const EXPORT_VERSION: i64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImportMode {
+    // keep the alarms already saved, and add the imported ones.
+    Merge,
+    // cancel every alarm already saved, and keep only the imported ones.
+    Replace
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub imported: usize,
+    // alarms whose message id was already saved, or appeared earlier in the file.
+    pub duplicates: usize,
+    // alarms that were saved before a replace, and weren't in the import.
+    pub removed: usize
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "imported {} alarms, skipped {} duplicates, removed {}", self.imported, self.duplicates, self.removed)
+    }
+}
+
+pub fn export() -> Result<String, String> {
+    let _lock = store::lock()?;
+    let (alarm_heap, _) = store::read()?;
+    let alarms = alarm_heap.iter().map(Alarm::to_json).collect();
+    let document = Value::Object(HashMap::from([
+        ("version".to_string(), Value::Number(IntOrFloat::Int(EXPORT_VERSION))),
+        ("alarms".to_string(), Value::Array(alarms))
+    ]));
+    Ok(json::stringify_pretty(&document))
+}
+
+fn parse_export(file_contents: &[u8]) -> Result<Vec<Alarm>, String> {
+    let Ok((Value::Object(document), end_index)) = json::parse_value(file_contents, 0) else {
+        return Err("import file is not a json object".to_string());
+    };
+    if end_index != file_contents.len() {
+        return Err("import file has something after the json object".to_string());
+    }
+    let Some(Value::Number(version)) = document.get("version") else {
+        return Err("import file has no version".to_string());
+    };
+    if version.as_int() != EXPORT_VERSION {
+        return Err(format!("import file has unknown version {}", version.as_int()));
+    }
+    let Some(Value::Array(saved_alarms)) = document.get("alarms") else {
+        return Err("import file has no alarms".to_string());
+    };
+
+    let mut alarms = Vec::with_capacity(saved_alarms.len());
+    for (index, saved) in saved_alarms.iter().enumerate() {
+        let Value::Object(saved) = saved else {
+            return Err(format!("alarm {} in import file is not a json object", index));
+        };
+        match Alarm::from_json(saved) {
+            Ok(alarm) => alarms.push(alarm),
+            Err(what_happened) => {
+                return Err(format!("alarm {} in import file: {}", index, what_happened));
+            }
+        }
+    }
+    Ok(alarms)
+}
+
+// one change to the saved alarms.
+#[derive(Debug)]
+enum Change {
+    Create(Alarm),
+    // an imported alarm taking the place of the saved one with the same ids.
+    Replace(Alarm, Alarm),
+    Cancel(Alarm)
+}
+
+// works out everything an import will change, before anything is changed.
+// the creates and replaces come before the cancels,
+// so if it fails partway, a replace hasn't already thrown away the old alarms.
+fn plan(saved: &AlarmHeap, alarms: Vec<Alarm>, mode: ImportMode) -> (Vec<Change>, ImportSummary) {
+    let mut changes = vec![];
+    let mut summary = ImportSummary::default();
+    let mut saved: HashMap<String, &Alarm> = saved.iter()
+        .map(|alarm| (alarm.message_id.to_string(), alarm))
+        .collect();
+
+    let mut seen_message_ids = HashSet::new();
+    for alarm in alarms {
+        if !seen_message_ids.insert(alarm.message_id.to_string()) {
+            summary.duplicates += 1;
+            continue;
+        }
+        match (mode, saved.get(&alarm.message_id)) {
+            (ImportMode::Merge, Some(_)) => {
+                summary.duplicates += 1;
+                continue;
+            },
+            (ImportMode::Replace, Some(old)) if old.channel_id == alarm.channel_id => {
+                let old = saved.remove(&alarm.message_id).expect("it was just found");
+                changes.push(Change::Replace(old.clone(), alarm));
+            },
+            _ => {
+                changes.push(Change::Create(alarm));
+            }
+        }
+        summary.imported += 1;
+    }
+
+    if mode == ImportMode::Replace {
+        let mut leftovers: Vec<&Alarm> = saved.into_values().collect();
+        leftovers.sort_by(|a, b| a.message_id.cmp(&b.message_id));
+        for alarm in leftovers {
+            changes.push(Change::Cancel(alarm.clone()));
+            summary.removed += 1;
+        }
+    }
+    (changes, summary)
+}
+
+fn apply(change: &Change) -> Result<(), String> {
+    match change {
+        Change::Create(alarm) => store::create(alarm),
+        Change::Replace(_, alarm) => store::update(alarm),
+        Change::Cancel(alarm) => store::cancel(alarm)
+    }
+}
+
+fn undo(change: &Change) -> Result<(), String> {
+    match change {
+        Change::Create(alarm) => store::cancel(alarm),
+        Change::Replace(old, _) => store::update(old),
+        Change::Cancel(alarm) => store::create(alarm)
+    }
+}
+
+pub fn import(document: &[u8], mode: ImportMode) -> Result<ImportSummary, String> {
+    // parse everything first, so a bad file doesn't leave a half finished import.
+    let alarms = parse_export(document)?;
+    let _lock = store::lock()?;
+    let (alarm_heap, _) = store::read()?;
+    let (changes, summary) = plan(&alarm_heap, alarms, mode);
+
+    for (done, change) in changes.iter().enumerate() {
+        let Err(what_happened) = apply(change) else {
+            continue;
+        };
+        // put back what was changed before it failed, newest first.
+        for change in changes[..done].iter().rev() {
+            if let Err(what_happened) = undo(change) {
+                println!("backup: failed to undo {:?}: {}", change, what_happened);
+            }
+        }
+        return Err(format!("{}
+import failed, so the alarms were put back how they were", what_happened));
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+
+    const CHANNEL_ID: &str = "01ARZ3NDEKTSV4RRFFQ69G5FAV";
+
+    fn alarm(message_id: &str, what: &str) -> Alarm {
+        Alarm {
+            when: DateTime::from_timestamp(1_800_000_000, 0).unwrap().naive_utc(),
+            what: what.to_string(),
+            channel_id: CHANNEL_ID.to_string(),
+            message_id: message_id.to_string(),
+            author: None,
+            created: None,
+            recurrence: None,
+            target: None,
+            attempts: 0,
+            reacted: false,
+            retry_at: None
+        }
+    }
+
+    fn document(alarms: &[Alarm]) -> String {
+        json::stringify(&Value::Object(HashMap::from([
+            ("version".to_string(), Value::Number(IntOrFloat::Int(EXPORT_VERSION))),
+            ("alarms".to_string(), Value::Array(alarms.iter().map(Alarm::to_json).collect()))
+        ])))
+    }
+
+    // each change as (what it does, message id, text of the alarm it leaves).
+    fn describe(changes: &[Change]) -> Vec<(&str, &str, &str)> {
+        changes.iter().map(|change| match change {
+            Change::Create(alarm) => ("create", alarm.message_id.as_str(), alarm.what.as_str()),
+            Change::Replace(_, alarm) => ("replace", alarm.message_id.as_str(), alarm.what.as_str()),
+            Change::Cancel(alarm) => ("cancel", alarm.message_id.as_str(), alarm.what.as_str())
+        }).collect()
+    }
+
+    #[test]
+    fn parses_exports() {
+        let alarms = parse_export(document(&[alarm("01BX5ZZKBKACTAV9WEVGEMMVRZ", "hello")]).as_bytes()).unwrap();
+        assert_eq!(alarms.len(), 1);
+        assert_eq!(alarms[0].message_id, "01BX5ZZKBKACTAV9WEVGEMMVRZ");
+        assert_eq!(alarms[0].what, "hello");
+
+        assert!(parse_export(br#"{"version":1,"alarms":[]}"#).unwrap().is_empty());
+        assert_eq!(parse_export(b"[]"), Err("import file is not a json object".to_string()));
+        assert_eq!(parse_export(br#"{"version":1,"alarms":[]} {}"#), Err("import file has something after the json object".to_string()));
+        assert_eq!(parse_export(br#"{"alarms":[]}"#), Err("import file has no version".to_string()));
+        assert_eq!(parse_export(br#"{"version":2,"alarms":[]}"#), Err("import file has unknown version 2".to_string()));
+        assert_eq!(parse_export(br#"{"version":1}"#), Err("import file has no alarms".to_string()));
+        assert_eq!(parse_export(br#"{"version":1,"alarms":[1]}"#), Err("alarm 0 in import file is not a json object".to_string()));
+        assert!(parse_export(br#"{"version":1,"alarms":[{}]}"#).unwrap_err().starts_with("alarm 0 in import file: "));
+    }
+
+    #[test]
+    fn merging_skips_duplicates() {
+        let mut saved = AlarmHeap::default();
+        saved.push(alarm("01BX5ZZKBKACTAV9WEVGEMMVR0", "saved"));
+        let imported = vec![
+            alarm("01BX5ZZKBKACTAV9WEVGEMMVR0", "already saved"),
+            alarm("01BX5ZZKBKACTAV9WEVGEMMVR1", "new"),
+            alarm("01BX5ZZKBKACTAV9WEVGEMMVR1", "twice in the file")
+        ];
+        let (changes, summary) = plan(&saved, imported, ImportMode::Merge);
+        assert_eq!(describe(&changes), vec![("create", "01BX5ZZKBKACTAV9WEVGEMMVR1", "new")]);
+        assert_eq!(summary.to_string(), "imported 1 alarms, skipped 2 duplicates, removed 0");
+    }
+
+    #[test]
+    fn replacing_cancels_the_rest_last() {
+        let mut saved = AlarmHeap::default();
+        saved.push(alarm("01BX5ZZKBKACTAV9WEVGEMMVR0", "old"));
+        saved.push(alarm("01BX5ZZKBKACTAV9WEVGEMMVR1", "not in the import"));
+        let imported = vec![
+            alarm("01BX5ZZKBKACTAV9WEVGEMMVR0", "new version"),
+            alarm("01BX5ZZKBKACTAV9WEVGEMMVR2", "new"),
+            alarm("01BX5ZZKBKACTAV9WEVGEMMVR2", "twice in the file")
+        ];
+        let (changes, summary) = plan(&saved, imported, ImportMode::Replace);
+        assert_eq!(describe(&changes), vec![
+            ("replace", "01BX5ZZKBKACTAV9WEVGEMMVR0", "new version"),
+            ("create", "01BX5ZZKBKACTAV9WEVGEMMVR2", "new"),
+            ("cancel", "01BX5ZZKBKACTAV9WEVGEMMVR1", "not in the import")
+        ]);
+        assert_eq!(summary.to_string(), "imported 2 alarms, skipped 1 duplicates, removed 1");
+    }
+}
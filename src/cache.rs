@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::json::Value;
+use crate::stoat_api::Channel;
+use crate::stoat_api::User;
+
+// a copy of the servers, channels, members and users the bot can see,
+// so looking them up doesn't need a request to the api every time.
+//
+// it's filled from the Ready event the websocket sends after authenticating,
+// which has everything in it, and then kept up to date by the events that follow.
+// each thing is kept as the json object stoat sent, since update events
+// are partial objects that get merged on top, plus a list of fields to clear.
+// see <https://developers.stoat.chat/developers/events/protocol.html>
+
+type Object = HashMap<String, Value>;
+
+#[derive(Default)]
+struct Cache {
+    servers: HashMap<String, Object>,
+    channels: HashMap<String, Object>,
+    // keyed by (server id, user id).
+    members: HashMap<(String, String), Object>,
+    users: HashMap<String, Object>
+}
+
+static CACHE: Mutex<Option<Cache>> = Mutex::new(None);
+
+// the permission bits the bot cares about.
+pub const SEND_EMBEDS: u64 = 1 << 26;
+pub const MASQUERADE: u64 = 1 << 28;
+
+fn string_field<'a>(object: &'a Object, name: &str) -> Option<&'a str> {
+    match object.get(name) {
+        Some(Value::String(text)) => Some(text),
+        _ => None
+    }
+}
+
+// the "_id" of every object in the array.
+fn objects_by_id(array: Option<&Value>) -> HashMap<String, Object> {
+    let Some(Value::Array(array)) = array else {
+        return HashMap::new();
+    };
+    array.iter()
+        .filter_map(|value| match value {
+            Value::Object(object) => Some((string_field(object, "_id")?.to_string(), object.clone())),
+            _ => None
+        })
+        .collect()
+}
+
+// members' ids are {"server":<server id>,"user":<user id>}.
+fn member_key(id: Option<&Value>) -> Option<(String, String)> {
+    let Some(Value::Object(id)) = id else {
+        return None;
+    };
+    Some((string_field(id, "server")?.to_string(), string_field(id, "user")?.to_string()))
+}
+
+// stoat names the fields to clear in PascalCase, like "DisplayName",
+// while the fields themselves are snake_case, like "display_name".
+fn field_name(clear: &str) -> String {
+    let mut name = String::new();
+    for character in clear.chars() {
+        if character.is_ascii_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(character.to_ascii_lowercase());
+    }
+    name
+}
+
+// applies an update event's "data" and "clear" to the object.
+fn update(object: &mut Object, event: &Object) {
+    if let Some(Value::Object(data)) = event.get("data") {
+        for (name, value) in data {
+            object.insert(name.to_string(), value.clone());
+        }
+    }
+    if let Some(Value::Array(clear)) = event.get("clear") {
+        for field in clear {
+            if let Value::String(field) = field {
+                object.remove(&field_name(field));
+            }
+        }
+    }
+}
+
+impl Cache {
+    fn from_ready(ready: &Object) -> Self {
+        let mut members = HashMap::new();
+        if let Some(Value::Array(ready_members)) = ready.get("members") {
+            for member in ready_members {
+                if let Value::Object(member) = member
+                    && let Some(key) = member_key(member.get("_id"))
+                {
+                    members.insert(key, member.clone());
+                }
+            }
+        }
+        Self {
+            servers: objects_by_id(ready.get("servers")),
+            channels: objects_by_id(ready.get("channels")),
+            members,
+            users: objects_by_id(ready.get("users"))
+        }
+    }
+
+    fn apply(&mut self, event: &Object) {
+        let Some(event_type) = string_field(event, "type") else {
+            return;
+        };
+        let id = string_field(event, "id").map(str::to_string);
+        match (event_type, id) {
+            ("ServerCreate", Some(id)) => {
+                if let Some(Value::Object(server)) = event.get("server") {
+                    self.servers.insert(id, server.clone());
+                }
+                self.channels.extend(objects_by_id(event.get("channels")));
+            },
+            ("ServerUpdate", Some(id)) => {
+                if let Some(server) = self.servers.get_mut(&id) {
+                    update(server, event);
+                }
+            },
+            ("ServerDelete", Some(id)) => {
+                self.servers.remove(&id);
+                self.channels.retain(|_, channel| string_field(channel, "server") != Some(&id));
+                self.members.retain(|(server_id, _), _| *server_id != id);
+            },
+            ("ServerRoleUpdate", Some(id)) => {
+                let Some(Value::String(role_id)) = event.get("role_id") else {
+                    return;
+                };
+                let Some(server) = self.servers.get_mut(&id) else {
+                    return;
+                };
+                let Value::Object(roles) = server.entry("roles".to_string()).or_insert_with(|| Value::Object(HashMap::new())) else {
+                    return;
+                };
+                let Value::Object(role) = roles.entry(role_id.to_string()).or_insert_with(|| Value::Object(HashMap::new())) else {
+                    return;
+                };
+                update(role, event);
+            },
+            ("ServerRoleDelete", Some(id)) => {
+                if let Some(Value::String(role_id)) = event.get("role_id")
+                    && let Some(Value::Object(roles)) = self.servers.get_mut(&id).and_then(|server| server.get_mut("roles"))
+                {
+                    roles.remove(role_id);
+                }
+            },
+            // the channel itself is the event, with "type" added.
+            ("ChannelCreate", _) => {
+                if let Some(channel_id) = string_field(event, "_id") {
+                    let mut channel = event.clone();
+                    channel.remove("type");
+                    self.channels.insert(channel_id.to_string(), channel);
+                }
+            },
+            ("ChannelUpdate", Some(id)) => {
+                if let Some(channel) = self.channels.get_mut(&id) {
+                    update(channel, event);
+                }
+            },
+            ("ChannelDelete", Some(id)) => {
+                self.channels.remove(&id);
+            },
+            ("ServerMemberJoin", Some(id)) => {
+                let Some(user_id) = string_field(event, "user") else {
+                    return;
+                };
+                let member = match event.get("member") {
+                    Some(Value::Object(member)) => member.clone(),
+                    _ => HashMap::new()
+                };
+                self.members.insert((id, user_id.to_string()), member);
+            },
+            ("ServerMemberUpdate", _) => {
+                if let Some(key) = member_key(event.get("id"))
+                    && let Some(member) = self.members.get_mut(&key)
+                {
+                    update(member, event);
+                }
+            },
+            ("ServerMemberLeave", Some(id)) => {
+                if let Some(user_id) = string_field(event, "user") {
+                    self.members.remove(&(id, user_id.to_string()));
+                }
+            },
+            ("UserUpdate", Some(id)) => {
+                if let Some(user) = self.users.get_mut(&id) {
+                    update(user, event);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn display_name(&self, server_id: Option<&str>, user_id: &str) -> Option<String> {
+        if let Some(server_id) = server_id
+            && let Some(member) = self.members.get(&(server_id.to_string(), user_id.to_string()))
+            && let Some(nickname) = string_field(member, "nickname")
+        {
+            return Some(nickname.to_string());
+        }
+        let user = User::from_json(self.users.get(user_id)?)?;
+        Some(user.name().to_string())
+    }
+
+    fn dm_channel(&self, user_id: &str) -> Option<String> {
+        self.channels.iter()
+            .find(|(_, channel)| {
+                string_field(channel, "channel_type") == Some("DirectMessage")
+                    && matches!(channel.get("recipients"), Some(Value::Array(recipients)) if recipients.contains(&Value::String(user_id.to_string())))
+            })
+            .map(|(channel_id, _)| channel_id.to_string())
+    }
+
+    // the member's roles, highest rank first, so that applying
+    // their overrides in order leaves the lower ranks winning.
+    fn member_roles(&self, server_id: &str, user_id: &str) -> Vec<String> {
+        let Some(member) = self.members.get(&(server_id.to_string(), user_id.to_string())) else {
+            return Vec::new();
+        };
+        let (Some(Value::Array(member_roles)), Some(Value::Object(roles))) = (member.get("roles"), self.servers.get(server_id).and_then(|server| server.get("roles"))) else {
+            return Vec::new();
+        };
+        let mut member_roles: Vec<(i64, String)> = member_roles.iter()
+            .filter_map(|role_id| match role_id {
+                Value::String(role_id) => match roles.get(role_id) {
+                    Some(Value::Object(role)) => match role.get("rank") {
+                        Some(Value::Number(rank)) => Some((rank.as_int(), role_id.to_string())),
+                        _ => Some((i64::MAX, role_id.to_string()))
+                    },
+                    _ => None
+                },
+                _ => None
+            })
+            .collect();
+        member_roles.sort_by_key(|(rank, _)| std::cmp::Reverse(*rank));
+        member_roles.into_iter().map(|(_, role_id)| role_id).collect()
+    }
+
+    // the permissions the user has across the whole server,
+    // before any channel's overrides are taken into account.
+    fn server_permissions(&self, server_id: &str, user_id: &str) -> Option<u64> {
+        let server = self.servers.get(server_id)?;
+        if string_field(server, "owner") == Some(user_id) {
+            return Some(u64::MAX);
+        }
+        self.members.get(&(server_id.to_string(), user_id.to_string()))?;
+        let mut permissions = match server.get("default_permissions") {
+            Some(Value::Number(default_permissions)) => default_permissions.as_int() as u64,
+            _ => 0
+        };
+        let Some(Value::Object(roles)) = server.get("roles") else {
+            return Some(permissions);
+        };
+        for role_id in self.member_roles(server_id, user_id) {
+            if let Some(Value::Object(role)) = roles.get(&role_id) {
+                permissions = overridden(permissions, role.get("permissions"));
+            }
+        }
+        Some(permissions)
+    }
+
+    // the server's permissions, then the channel's overrides for everyone,
+    // then its overrides for each of the user's roles.
+    // only channels in a server have permissions to work out.
+    fn channel_permissions(&self, channel_id: &str, user_id: &str) -> Option<u64> {
+        let channel = self.channels.get(channel_id)?;
+        let server_id = string_field(channel, "server")?;
+        let mut permissions = self.server_permissions(server_id, user_id)?;
+        if permissions == u64::MAX {
+            return Some(permissions);
+        }
+        permissions = overridden(permissions, channel.get("default_permissions"));
+        let Some(Value::Object(role_permissions)) = channel.get("role_permissions") else {
+            return Some(permissions);
+        };
+        for role_id in self.member_roles(server_id, user_id) {
+            permissions = overridden(permissions, role_permissions.get(&role_id));
+        }
+        Some(permissions)
+    }
+}
+
+// each override allows ("a") and denies ("d") some permissions.
+fn overridden(mut permissions: u64, overrides: Option<&Value>) -> u64 {
+    let Some(Value::Object(overrides)) = overrides else {
+        return permissions;
+    };
+    if let Some(Value::Number(allow)) = overrides.get("a") {
+        permissions |= allow.as_int() as u64;
+    }
+    if let Some(Value::Number(deny)) = overrides.get("d") {
+        permissions &= !(deny.as_int() as u64);
+    }
+    permissions
+}
+
+// replaces everything in the cache with what's in the Ready event.
+pub fn load_ready(ready: &Object) {
+    if let Ok(mut cache) = CACHE.lock() {
+        *cache = Some(Cache::from_ready(ready));
+    }
+}
+
+// keeps the cache up to date.  events that don't change anything it holds are ignored.
+pub fn apply(event: &Object) {
+    if let Ok(mut cache) = CACHE.lock() {
+        cache.get_or_insert_default().apply(event);
+    }
+}
+
+pub fn channel(channel_id: &str) -> Option<Channel> {
+    let cache = CACHE.lock().ok()?;
+    Channel::from_json(cache.as_ref()?.channels.get(channel_id)?)
+}
+
+pub fn user(user_id: &str) -> Option<User> {
+    let cache = CACHE.lock().ok()?;
+    User::from_json(cache.as_ref()?.users.get(user_id)?)
+}
+
+pub fn server_name(server_id: &str) -> Option<String> {
+    let cache = CACHE.lock().ok()?;
+    string_field(cache.as_ref()?.servers.get(server_id)?, "name").map(str::to_string)
+}
+
+// what to call the user: their nickname in the server if they have one,
+// otherwise their display name, otherwise their username.
+pub fn display_name(server_id: Option<&str>, user_id: &str) -> Option<String> {
+    let cache = CACHE.lock().ok()?;
+    cache.as_ref()?.display_name(server_id, user_id)
+}
+
+// the bot's dm channel with the user, if one's been opened.
+pub fn dm_channel(user_id: &str) -> Option<String> {
+    let cache = CACHE.lock().ok()?;
+    cache.as_ref()?.dm_channel(user_id)
+}
+
+pub fn server_permissions(server_id: &str, user_id: &str) -> Option<u64> {
+    let cache = CACHE.lock().ok()?;
+    cache.as_ref()?.server_permissions(server_id, user_id)
+}
+
+// None if the channel isn't in a server, or the cache doesn't know the user's a member.
+pub fn channel_permissions(channel_id: &str, user_id: &str) -> Option<u64> {
+    let cache = CACHE.lock().ok()?;
+    cache.as_ref()?.channel_permissions(channel_id, user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    fn object(text: &str) -> Object {
+        let Ok((Value::Object(object), _)) = json::parse_value(text.as_bytes(), 0) else {
+            panic!("not a json object: {}", text);
+        };
+        object
+    }
+
+    fn ready() -> Cache {
+        Cache::from_ready(&object(r#"{
+            "type": "Ready",
+            "users": [
+                {"_id": "U1", "username": "alice", "discriminator": "0001", "display_name": "Alice"},
+                {"_id": "U2", "username": "bob", "discriminator": "0002"}
+            ],
+            "servers": [{
+                "_id": "S1", "owner": "U1", "name": "the server", "channels": ["C1"],
+                "default_permissions": 1,
+                "roles": {
+                    "R1": {"name": "mods", "rank": 0, "permissions": {"a": 6, "d": 0}},
+                    "R2": {"name": "muted", "rank": 5, "permissions": {"a": 8, "d": 3}}
+                }
+            }],
+            "channels": [
+                {"_id": "C1", "channel_type": "TextChannel", "server": "S1", "name": "general"},
+                {"_id": "D1", "channel_type": "DirectMessage", "recipients": ["BOT", "U2"], "active": true}
+            ],
+            "members": [
+                {"_id": {"server": "S1", "user": "U1"}},
+                {"_id": {"server": "S1", "user": "U2"}, "nickname": "bobby", "roles": ["R1", "R2"]}
+            ]
+        }"#))
+    }
+
+    #[test]
+    fn lookups() {
+        let cache = ready();
+        assert_eq!(Channel::from_json(&cache.channels["C1"]).unwrap().server.as_deref(), Some("S1"));
+        assert_eq!(cache.display_name(Some("S1"), "U2").as_deref(), Some("bobby"));
+        assert_eq!(cache.display_name(None, "U2").as_deref(), Some("bob"));
+        assert_eq!(cache.display_name(Some("S1"), "U1").as_deref(), Some("Alice"));
+        assert_eq!(cache.display_name(Some("S1"), "U3"), None);
+        assert_eq!(cache.dm_channel("U2").as_deref(), Some("D1"));
+        assert_eq!(cache.dm_channel("U1"), None);
+    }
+
+    #[test]
+    fn permissions() {
+        let mut cache = ready();
+        assert_eq!(cache.server_permissions("S1", "U1"), Some(u64::MAX));
+        // 1, then muted (rank 5) allows 8 and denies 3, then mods (rank 0) allows 6.
+        assert_eq!(cache.server_permissions("S1", "U2"), Some(14));
+        assert_eq!(cache.server_permissions("S1", "U3"), None);
+        assert_eq!(cache.channel_permissions("C1", "U2"), Some(14));
+        assert_eq!(cache.channel_permissions("D1", "U2"), None);
+
+        // everyone loses 4 in the channel, muted gets 1 back, then mods gets 4 back.
+        cache.apply(&object(r#"{"type": "ChannelUpdate", "id": "C1", "data": {
+            "default_permissions": {"a": 0, "d": 4},
+            "role_permissions": {"R1": {"a": 4, "d": 0}, "R2": {"a": 1, "d": 0}}
+        }, "clear": []}"#));
+        assert_eq!(cache.channel_permissions("C1", "U2"), Some(15));
+        assert_eq!(cache.channel_permissions("C1", "U1"), Some(u64::MAX));
+        cache.apply(&object(r#"{"type": "ChannelUpdate", "id": "C1", "data": {}, "clear": ["RolePermissions"]}"#));
+        assert_eq!(cache.channel_permissions("C1", "U2"), Some(10));
+    }
+
+    #[test]
+    fn updates() {
+        let mut cache = ready();
+        cache.apply(&object(r#"{"type": "ServerMemberUpdate", "id": {"server": "S1", "user": "U2"}, "data": {}, "clear": ["Nickname"]}"#));
+        assert_eq!(cache.display_name(Some("S1"), "U2").as_deref(), Some("bob"));
+        cache.apply(&object(r#"{"type": "UserUpdate", "id": "U2", "data": {"display_name": "Robert"}, "clear": []}"#));
+        assert_eq!(cache.display_name(Some("S1"), "U2").as_deref(), Some("Robert"));
+        cache.apply(&object(r#"{"type": "UserUpdate", "id": "U2", "data": {}, "clear": ["DisplayName"]}"#));
+        assert_eq!(cache.display_name(Some("S1"), "U2").as_deref(), Some("bob"));
+
+        cache.apply(&object(r#"{"type": "ChannelCreate", "_id": "C2", "channel_type": "TextChannel", "server": "S1", "name": "alarms"}"#));
+        assert_eq!(Channel::from_json(&cache.channels["C2"]).unwrap().name.as_deref(), Some("alarms"));
+        cache.apply(&object(r#"{"type": "ChannelUpdate", "id": "C2", "data": {"name": "reminders"}, "clear": []}"#));
+        assert_eq!(string_field(&cache.channels["C2"], "name"), Some("reminders"));
+
+        cache.apply(&object(r#"{"type": "ServerRoleUpdate", "id": "S1", "role_id": "R2", "data": {"name": "quiet"}, "clear": []}"#));
+        let Some(Value::Object(roles)) = cache.servers["S1"].get("roles") else {
+            panic!("the server lost its roles");
+        };
+        let Some(Value::Object(role)) = roles.get("R2") else {
+            panic!("the role went missing");
+        };
+        assert_eq!(string_field(role, "name"), Some("quiet"));
+        cache.apply(&object(r#"{"type": "ServerRoleUpdate", "id": "S1", "role_id": "R2", "data": {"permissions": {"a": 0, "d": 0}}, "clear": []}"#));
+        assert_eq!(cache.server_permissions("S1", "U2"), Some(7));
+
+        cache.apply(&object(r#"{"type": "ServerMemberUpdate", "id": {"server": "S1", "user": "U2"}, "data": {"nickname": "rob"}, "clear": []}"#));
+        assert_eq!(cache.display_name(Some("S1"), "U2").as_deref(), Some("rob"));
+        cache.apply(&object(r#"{"type": "ServerMemberLeave", "id": "S1", "user": "U2"}"#));
+        assert_eq!(cache.display_name(Some("S1"), "U2").as_deref(), Some("bob"));
+        assert_eq!(cache.server_permissions("S1", "U2"), None);
+        cache.apply(&object(r#"{"type": "ServerDelete", "id": "S1"}"#));
+        assert!(cache.servers.is_empty());
+        assert!(!cache.channels.contains_key("C1"));
+        assert!(cache.members.is_empty());
+    }
+}
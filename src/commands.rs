@@ -0,0 +1,474 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use crate::alarm::Alarm;
+use crate::alarm_heap::AlarmHeap;
+use crate::history;
+use crate::json::Value;
+use crate::settings;
+use crate::stoat_api;
+use crate::store;
+use crate::timezones;
+
+// everything the bot does when it's mentioned goes through here.
+// messages are formatted like this:
+// "@bot_handle <command> <arguments>"
+// the first word after the mention picks the command (by its name or one of its aliases),
+// and whatever's after that is handed to it as its arguments.
+// if it's not a command, but has "in " followed by a time somewhere, it sets an alarm like "in" does.
+// anything else gets no reply.
+// to add a command, write a function to run it and put it in COMMANDS.
+// "help" is made from COMMANDS, so it doesn't need updating by hand.
+
+// what a command has to work with.
+pub struct Invocation<'a> {
+    pub message: &'a HashMap<String, Value>,
+    // where the command was given, and where the reply goes.
+    pub channel_id: &'a str,
+    pub author: &'a str,
+    // everything after the command's name.
+    pub arguments: &'a str,
+    pub alarm_heap: &'a Arc<Mutex<AlarmHeap>>
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    // what goes after the name, for help.
+    pub arguments: &'static str,
+    pub description: &'static str,
+    // gives back what to reply with, if anything.
+    // an error means the arguments weren't right, and is shown along with how to use the command.
+    pub run: fn(&Invocation) -> Result<Option<String>, String>
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "in",
+        aliases: &[],
+        arguments: "<time> <message>",
+        description: "sets an alarm.  the time is any of days, hours, minutes and seconds, like 1d2h30m10s.  \"in 1h30m take the bread out\" says \"take the bread out\" an hour and a half from now.",
+        run: set_alarm
+    },
+    Command {
+        name: "list",
+        aliases: &["alarms"],
+        arguments: "",
+        description: "lists the alarms you've set in this channel that haven't gone off yet.",
+        run: list_alarms
+    },
+    Command {
+        name: "cancel",
+        aliases: &["delete", "remove"],
+        arguments: "<number from list, or the id of the message that set it>",
+        description: "cancels one of your alarms in this channel.",
+        run: cancel_alarm
+    },
+    Command {
+        name: "history",
+        aliases: &[],
+        arguments: "",
+        description: "shows your alarms in this channel that have gone off lately.",
+        run: show_history
+    },
+    Command {
+        name: "timezone",
+        aliases: &["tz"],
+        arguments: "[offset]",
+        description: "shows your timezone, or sets it to an offset from utc like \"utc+2\" or \"-05:30\".  times in list and history are shown in it.",
+        run: timezone
+    },
+    Command {
+        name: "license",
+        aliases: &["source"],
+        arguments: "",
+        description: "says what license the bot is under, and where its source code is.",
+        run: license
+    },
+    Command {
+        name: "help",
+        aliases: &["commands"],
+        arguments: "[command]",
+        description: "lists what the bot can do, or explains one command.",
+        run: help
+    }
+];
+
+// the most alarms that list shows at once.
+const LIST_LIMIT: usize = 20;
+
+// splits "<@bot> Cancel 2" into ("cancel", "2").
+// mentions before the command are skipped.
+fn split_command(content: &str) -> Option<(String, &str)> {
+    let mut rest = content.trim_start();
+    loop {
+        let word_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (word, after) = rest.split_at(word_end);
+        if word.is_empty() {
+            return None;
+        }
+        if word.starts_with("<@") && word.ends_with('>') {
+            rest = after.trim_start();
+            continue;
+        }
+        return Some((word.to_lowercase(), after.trim()));
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    let name = name.to_lowercase();
+    COMMANDS.iter().find(|command| command.name == name || command.aliases.contains(&name.as_str()))
+}
+
+fn usage(command: &Command) -> String {
+    if command.arguments.is_empty() {
+        format!("`@bot {}`", command.name)
+    } else {
+        format!("`@bot {} {}`", command.name, command.arguments)
+    }
+}
+
+// runs whichever command the message asks for, and replies with what it says.
+pub fn dispatch(message: &HashMap<String, Value>, alarm_heap: &Arc<Mutex<AlarmHeap>>) {
+    let (
+        Some(Value::String(channel_id)),
+        Some(Value::String(author)),
+        Some(Value::String(content))
+    ) = (message.get("channel"), message.get("author"), message.get("content")) else {
+        return;
+    };
+
+    let invocation = |arguments| Invocation {
+        message,
+        channel_id,
+        author,
+        arguments,
+        alarm_heap
+    };
+    let reply = match split_command(content).and_then(|(name, arguments)| Some((find(&name)?, arguments))) {
+        Some((command, arguments)) => match (command.run)(&invocation(arguments)) {
+            Ok(reply) => reply,
+            Err(problem) => Some(format!("{}\nusage: {}", problem, usage(command)))
+        },
+        // before there were commands, "in " could go anywhere, like "@bot remind me in 1h tea".
+        // anything else (like "thanks @bot") is just people talking, so it's left alone.
+        None => match content.find("in ") {
+            Some(index) => set_alarm(&invocation(&content[index + "in ".len()..])).ok().flatten(),
+            None => None
+        }
+    };
+
+    if let Some(reply) = reply
+        && let Err(what_happened) = stoat_api::wait_if_rate_limited(|| stoat_api::post_message(channel_id, &reply))
+    {
+        println!("commands: {}\nfailed to reply in {}", what_happened, channel_id);
+    }
+}
+
+fn set_alarm(invocation: &Invocation) -> Result<Option<String>, String> {
+    let Some(alarm) = Alarm::from_message(invocation.message, invocation.arguments) else {
+        return Err("i couldn't tell when that's for, or there was nothing to say when it goes off.".to_string());
+    };
+    // the message has been handled before, like when it's caught up on after a restart
+    // and the alarm it set was loaded from the store.
+    if invocation.alarm_heap.lock().is_ok_and(|heap_lock| heap_lock.contains(&alarm.message_id)) {
+        return Ok(None);
+    }
+    if let Err(what_happened) = store::create(&alarm) {
+        println!("failed to save alarm: {}", what_happened);
+    }
+    let confirm_reaction = settings::for_channel(&alarm.channel_id).confirm_reaction;
+    if let Err(what_happened) = stoat_api::wait_if_rate_limited(|| stoat_api::react(&alarm.channel_id, &alarm.message_id, &confirm_reaction)) {
+        println!("commands: {}\nfailed to react {:?}", what_happened, &alarm);
+    }
+    let mut heap_lock = invocation.alarm_heap.lock().expect("alarm heap mutex has been poisoned.  ending event listener.");
+    heap_lock.push(alarm);
+    Ok(None)
+}
+
+// the author's alarms in the channel, soonest first.
+// only the channel's, so listing in one channel can't show what was set in another (or in a dm).
+fn alarms_of(invocation: &Invocation) -> Vec<Alarm> {
+    let Ok(heap_lock) = invocation.alarm_heap.lock() else {
+        println!("commands: alarm heap mutex has been poisoned");
+        return Vec::new();
+    };
+    let mut alarms: Vec<Alarm> = heap_lock.iter()
+        .filter(|alarm| alarm.author.as_deref() == Some(invocation.author) && alarm.channel_id == invocation.channel_id)
+        .cloned()
+        .collect();
+    alarms.sort_by_key(|alarm| alarm.due());
+    alarms
+}
+
+fn list_alarms(invocation: &Invocation) -> Result<Option<String>, String> {
+    if !invocation.arguments.is_empty() {
+        return Err("list doesn't take anything after it.".to_string());
+    }
+    let alarms = alarms_of(invocation);
+    if alarms.is_empty() {
+        return Ok(Some("you don't have any alarms set in this channel.".to_string()));
+    }
+    let timezone = timezones::of(invocation.author);
+    let mut reply = format!("your alarms ({}):", alarms.len());
+    for (number, alarm) in alarms.iter().take(LIST_LIMIT).enumerate() {
+        reply.push_str(&format!(
+            "\n{}. {} (at {}, set by message {})",
+            number + 1,
+            alarm.what,
+            timezones::format(&alarm.when, timezone),
+            alarm.message_id
+        ));
+    }
+    if alarms.len() > LIST_LIMIT {
+        reply.push_str(&format!("\n...and {} more.", alarms.len() - LIST_LIMIT));
+    }
+    Ok(Some(reply))
+}
+
+// which of the alarms the user meant, by its number in the list or by its id.
+fn choose<'a>(alarms: &'a [Alarm], which: &str) -> Option<&'a Alarm> {
+    match which.parse::<usize>() {
+        Ok(number) => number.checked_sub(1).and_then(|index| alarms.get(index)),
+        Err(_) => alarms.iter().find(|alarm| alarm.message_id.eq_ignore_ascii_case(which))
+    }
+}
+
+fn cancel_alarm(invocation: &Invocation) -> Result<Option<String>, String> {
+    if invocation.arguments.is_empty() {
+        return Err("which alarm?".to_string());
+    }
+    let alarms = alarms_of(invocation);
+    let Some(chosen) = choose(&alarms, invocation.arguments) else {
+        return Err(format!("you don't have an alarm \"{}\".  `@bot list` shows the ones you do.", invocation.arguments));
+    };
+
+    let removed = match invocation.alarm_heap.lock() {
+        Ok(mut heap_lock) => heap_lock.remove(&chosen.message_id),
+        Err(_) => {
+            println!("commands: alarm heap mutex has been poisoned");
+            return Ok(None);
+        }
+    };
+    // it went off between being looked up and being removed.
+    let Some(removed) = removed else {
+        return Ok(Some(format!("too late, \"{}\" has already gone off.", chosen.what)));
+    };
+    if let Err(what_happened) = store::cancel(&removed) {
+        println!("failed to delete cancelled alarm: {}", what_happened);
+    }
+    Ok(Some(format!("cancelled \"{}\".", removed.what)))
+}
+
+fn show_history(invocation: &Invocation) -> Result<Option<String>, String> {
+    const HOW_MANY: usize = 10;
+    if !invocation.arguments.is_empty() {
+        return Err("history doesn't take anything after it.".to_string());
+    }
+    let entries = match history::recent_for(invocation.author, invocation.channel_id, HOW_MANY) {
+        Ok(entries) => entries,
+        Err(what_happened) => {
+            println!("commands: {}\nfailed to read history for {}", what_happened, invocation.author);
+            return Ok(None);
+        }
+    };
+    if entries.is_empty() {
+        return Ok(Some("none of your alarms in this channel have gone off lately.".to_string()));
+    }
+    let timezone = timezones::of(invocation.author);
+    let mut reply = format!("your last {} alarms:", entries.len());
+    for entry in &entries {
+        let status = match &entry.status {
+            history::Status::Delivered => "delivered",
+            history::Status::Failed(_) => "failed to deliver"
+        };
+        reply.push_str(&format!(
+            "\n- {} (set for {}, went off {}, {})",
+            entry.alarm.what,
+            timezones::format(&entry.alarm.when, timezone),
+            timezones::format(&entry.fired, timezone),
+            status
+        ));
+    }
+    Ok(Some(reply))
+}
+
+fn timezone(invocation: &Invocation) -> Result<Option<String>, String> {
+    if invocation.arguments.is_empty() {
+        return Ok(Some(format!("your timezone is {}.", timezones::name(timezones::of(invocation.author)))));
+    }
+    let Some(offset) = timezones::parse(invocation.arguments) else {
+        return Err(format!("\"{}\" isn't an offset from utc i understand.", invocation.arguments));
+    };
+    if let Err(what_happened) = timezones::set(invocation.author, offset) {
+        println!("commands: {}\nfailed to save the timezone for {}", what_happened, invocation.author);
+        return Ok(Some("sorry, i couldn't save that.".to_string()));
+    }
+    Ok(Some(format!("your timezone is now {}.", timezones::name(offset))))
+}
+
+fn license(_invocation: &Invocation) -> Result<Option<String>, String> {
+    const AGPL3_MESSAGE: &str = "that's quite alarming is licensed under the gnu affero general public license version 3.  source code can be found at <https://github.com/shocktail39/thats-quite-alarming/>";
+    Ok(Some(AGPL3_MESSAGE.to_string()))
+}
+
+fn help(invocation: &Invocation) -> Result<Option<String>, String> {
+    if invocation.arguments.is_empty() {
+        let mut reply = "mention me with one of these:".to_string();
+        for command in COMMANDS {
+            reply.push_str(&format!("\n- {}", usage(command)));
+        }
+        reply.push_str("\n`@bot help <command>` explains one of them.");
+        return Ok(Some(reply));
+    }
+    let Some(command) = find(invocation.arguments) else {
+        return Err(format!("there's no command called \"{}\".", invocation.arguments));
+    };
+    let mut reply = format!("{}\n{}", usage(command), command.description);
+    if !command.aliases.is_empty() {
+        reply.push_str(&format!("\nalso works as: {}", command.aliases.join(", ")));
+    }
+    Ok(Some(reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splitting() {
+        assert_eq!(split_command("<@01J0000000000000000000B0TS> in 5m tea"), Some(("in".to_string(), "5m tea")));
+        assert_eq!(split_command("  Cancel   2 "), Some(("cancel".to_string(), "2")));
+        assert_eq!(split_command("<@A> <@B>\nhelp"), Some(("help".to_string(), "")));
+        assert_eq!(split_command("<@01J0000000000000000000B0TS>"), None);
+        assert_eq!(split_command(""), None);
+    }
+
+    #[test]
+    fn finding() {
+        assert_eq!(find("in").map(|command| command.name), Some("in"));
+        assert_eq!(find("TZ").map(|command| command.name), Some("timezone"));
+        assert_eq!(find("source").map(|command| command.name), Some("license"));
+        assert!(find("licence").is_none());
+    }
+
+    #[test]
+    fn names_are_unique() {
+        let mut names: Vec<&str> = COMMANDS.iter()
+            .flat_map(|command| std::iter::once(command.name).chain(command.aliases.iter().copied()))
+            .collect();
+        let count = names.len();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), count);
+    }
+
+    const CHANNEL_ID: &str = "01ARZ3NDEKTSV4RRFFQ69G5FAV";
+    const DM_ID: &str = "01BX5ZZKBKACTAV9WEVGEMMVRZ";
+    const AUTHOR_ID: &str = "01J0000000000000000000ATHR";
+    const SOMEONE_ELSE_ID: &str = "01J0000000000000000000THER";
+
+    // an alarm going off `minutes` after the epoch, set by message_id.
+    fn alarm(what: &str, channel_id: &str, author: &str, minutes: i64, message_id: &str) -> Alarm {
+        Alarm {
+            when: chrono::DateTime::from_timestamp(minutes * 60, 0).unwrap().naive_utc(),
+            what: what.to_string(),
+            channel_id: channel_id.to_string(),
+            message_id: message_id.to_string(),
+            author: Some(author.to_string()),
+            created: None,
+            recurrence: None,
+            target: None,
+            attempts: 0,
+            reacted: false,
+            retry_at: None
+        }
+    }
+
+    fn heap_of(alarms: Vec<Alarm>) -> Arc<Mutex<AlarmHeap>> {
+        let mut alarm_heap = AlarmHeap::default();
+        for alarm in alarms {
+            alarm_heap.push(alarm);
+        }
+        Arc::new(Mutex::new(alarm_heap))
+    }
+
+    #[test]
+    fn list_only_shows_this_channel() {
+        let message = HashMap::new();
+        let alarm_heap = heap_of(vec![
+            alarm("later", CHANNEL_ID, AUTHOR_ID, 20, "01J00000000000000000000002"),
+            alarm("sooner", CHANNEL_ID, AUTHOR_ID, 10, "01J00000000000000000000001"),
+            alarm("private", DM_ID, AUTHOR_ID, 5, "01J00000000000000000000003"),
+            alarm("not yours", CHANNEL_ID, SOMEONE_ELSE_ID, 1, "01J00000000000000000000004")
+        ]);
+        let invocation = Invocation {
+            message: &message,
+            channel_id: CHANNEL_ID,
+            author: AUTHOR_ID,
+            arguments: "",
+            alarm_heap: &alarm_heap
+        };
+        let reply = list_alarms(&invocation).unwrap().unwrap();
+        assert!(reply.starts_with("your alarms (2):\n1. sooner (at 1970-01-01 00:10 utc"), "{}", reply);
+        assert!(reply.contains("\n2. later"), "{}", reply);
+        assert!(!reply.contains("private") && !reply.contains("not yours"), "{}", reply);
+    }
+
+    #[test]
+    fn cancel_numbers_and_ownership() {
+        let message = HashMap::new();
+        let alarm_heap = heap_of(vec![
+            alarm("later", CHANNEL_ID, AUTHOR_ID, 20, "01J00000000000000000000002"),
+            alarm("sooner", CHANNEL_ID, AUTHOR_ID, 10, "01J00000000000000000000001"),
+            alarm("private", DM_ID, AUTHOR_ID, 5, "01J00000000000000000000003"),
+            alarm("not yours", CHANNEL_ID, SOMEONE_ELSE_ID, 1, "01J00000000000000000000004")
+        ]);
+        let invocation = |arguments| Invocation {
+            message: &message,
+            channel_id: CHANNEL_ID,
+            author: AUTHOR_ID,
+            arguments,
+            alarm_heap: &alarm_heap
+        };
+        // only picks which alarm to cancel, so the test doesn't touch the saved alarms.
+        let chosen = |arguments| {
+            let invocation = invocation(arguments);
+            choose(&alarms_of(&invocation), arguments).map(|alarm| alarm.what.clone())
+        };
+
+        // numbers count the author's alarms in the channel, soonest first, like list does.
+        assert_eq!(chosen("1").as_deref(), Some("sooner"));
+        assert_eq!(chosen("2").as_deref(), Some("later"));
+        assert_eq!(chosen("3"), None);
+        assert_eq!(chosen("0"), None);
+        assert_eq!(chosen("01j00000000000000000000001").as_deref(), Some("sooner"));
+        // someone else's alarm, and the author's own in another channel, can't be cancelled from here.
+        assert_eq!(chosen("01J00000000000000000000004"), None);
+        assert_eq!(chosen("01J00000000000000000000003"), None);
+        assert!(cancel_alarm(&invocation("01J00000000000000000000004")).is_err());
+        assert!(cancel_alarm(&invocation("")).is_err());
+        assert_eq!(alarm_heap.lock().unwrap().iter().count(), 4);
+    }
+
+    #[test]
+    fn help_lists_every_command() {
+        let message = HashMap::new();
+        let alarm_heap = Arc::new(Mutex::new(AlarmHeap::default()));
+        let invocation = |arguments| Invocation {
+            message: &message,
+            channel_id: "",
+            author: "",
+            arguments,
+            alarm_heap: &alarm_heap
+        };
+        let reply = help(&invocation("")).unwrap().unwrap();
+        for command in COMMANDS {
+            assert!(reply.contains(&usage(command)), "{}", reply);
+        }
+        let reply = help(&invocation("cancel")).unwrap().unwrap();
+        assert!(reply.contains("delete, remove"), "{}", reply);
+        assert!(help(&invocation("nonsense")).is_err());
+    }
+}
@@ -1,14 +1,60 @@
-pub const BOT_ID: &str = "put your bot's user id here";
+use std::time::Duration;
+
 pub const BOT_TOKEN: &str = "put your bot's secret token here";
 
 // this is where the bot will save alarms,
 // so that it can remember what alarms were set
 // if it goes offline.
 pub const WHERE_TO_SAVE: &str = "./alarms/";
+// saved alarms that can't be loaded get moved here,
+// so they can be looked at (and fixed by hand) later.
+pub const QUARANTINE_DIR: &str = "./quarantine/";
+// locked by whatever is using the saved alarms, so the bot and an import or export
+// can't run at once.  the lock goes away by itself when the program holding it stops,
+// so the file being left behind doesn't matter.
+pub const LOCK_FILE: &str = "./alarms.lock";
+// per-server settings, like which emoji to react with.
+// see settings.rs for what goes in them.
+pub const SETTINGS_DIR: &str = "./settings/";
+// alarms that have gone off are kept here,
+// for the "history" command.
+pub const HISTORY_FILE: &str = "./history.jsonl";
+// how many days to keep alarms in the history before forgetting them.
+pub const HISTORY_RETENTION_DAYS: u64 = 30;
+// the newest message the bot has seen in each channel,
+// so it can catch up on messages sent while it was offline.
+pub const LAST_SEEN_FILE: &str = "./last-seen.json";
+// each user's timezone, set with the "timezone" command,
+// so times can be shown to them in their own time.
+pub const TIMEZONES_FILE: &str = "./timezones.json";
+// alarms that can't be posted (like when the bot was kicked from the channel)
+// are moved here instead of being retried forever.
+pub const DEAD_LETTER_DIR: &str = "./dead-letter/";
+// how many times to try posting an alarm before moving it to DEAD_LETTER_DIR.
+// the wait between tries doubles each time, up to an hour.
+pub const MAX_DELIVERY_ATTEMPTS: u32 = 20;
+
+// user ids of other bots that are allowed to give the bot commands.
+// messages from any other bot are ignored, so two bots can't keep setting each other off.
+pub const ALLOWED_BOTS: &[&str] = &[];
+
+// set this to true to keep every alarm in one append-only journal file
+// instead of one file per alarm.  worth it if the bot runs off an sd card.
+pub const USE_JOURNAL: bool = false;
+// how many changes the journal holds before it's compacted into a snapshot.
+pub const JOURNAL_COMPACT_AFTER: usize = 1000;
 
 // if you're self-hosting your own stoat server,
-// then change the following to match your stoat server.
-pub const EVENT_SOCKET: (&str, u16) = ("events.stoat.chat", 443);
-pub const EVENT_ENDPOINT: &str = "wss://events.stoat.chat/";
-pub const HTTP_SOCKET: (&str, u16) = ("api.stoat.chat", 443);
-pub const HTTP_ENDPOINT: &str = "api.stoat.chat";
+// then change this to your server's api, like "https://stoat.example.com/api".
+// the api says where its event websocket is, so that doesn't need setting.
+pub const API_URL: &str = "https://api.stoat.chat";
+// or, without rebuilding, set this environment variable to the api's url instead.
+// plain "http://" urls work too, which is how the tests point the bot at a local mock server.
+pub const API_URL_VARIABLE: &str = "STOAT_API_URL";
+
+// how long to wait on the network before giving up,
+// so a connection that's died without closing can't hang the bot forever.
+// these apply to both the api and the event websocket.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
@@ -1,25 +1,43 @@
 use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::hash::RandomState;
+use std::io;
 use std::net::TcpStream;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
 
 use tungstenite::ClientRequestBuilder;
+use tungstenite::HandshakeError;
 use tungstenite::stream::MaybeTlsStream;
 use tungstenite::protocol::Message;
 use tungstenite::protocol::WebSocket;
 
-use crate::alarm::Alarm;
 use crate::alarm_heap::AlarmHeap;
+use crate::cache;
+use crate::commands;
 use crate::config;
-use crate::file;
+use crate::http;
+use crate::http::Timeouts;
 use crate::json;
+use crate::json::IntOrFloat;
 use crate::json::Value;
+use crate::last_seen;
 use crate::stoat_api;
+use crate::url;
 
 fn authenticate(stream: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<(), String> {
-    let auth_request = Message::Text(format!(r#"{{"type":"Authenticate","token":"{}"}}"#, config::BOT_TOKEN).into());
+    let auth_request = Value::Object(HashMap::from([
+        ("type".to_string(), Value::String("Authenticate".to_string())),
+        ("token".to_string(), Value::String(config::BOT_TOKEN.to_string()))
+    ]));
+    let auth_request = Message::Text(json::stringify(&auth_request).into());
     let Ok(()) = stream.send(auth_request) else {
         return Err("failed to send auth request to event websocket".to_string());
     };
@@ -52,22 +70,43 @@ fn wait_for_ready(stream: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<(
     if msg_type.as_str() != "Ready" {
         return Err("ready response from event websocket is invalid".to_string());
     }
+    cache::load_ready(&response);
     Ok(())
 }
 
-fn start_ws_stream() -> Result<WebSocket<MaybeTlsStream<TcpStream>>, String> {
+fn start_ws_stream(events_url: &str) -> Result<WebSocket<MaybeTlsStream<TcpStream>>, String> {
     let mut stream = {
-        let Ok(endpoint) = config::EVENT_ENDPOINT.parse() else {
-            return Err("make sure EVENT_ENDPOINT in config.rs is a valid url.".to_string());
+        let parsed = match url::parse(events_url) {
+            Ok(parsed) => parsed,
+            Err(what_happened) => {
+                return Err(format!("the event websocket url the api gave is invalid: {}", what_happened));
+            }
         };
-        let tls_request = ClientRequestBuilder::new(endpoint);
-        let Ok(tcp_stream) = TcpStream::connect(config::EVENT_SOCKET) else {
-            return Err("failed to start tcp session with event websocket".to_string());
+        if parsed.scheme != "ws" && parsed.scheme != "wss" {
+            return Err(format!("the event websocket url the api gave, {:?}, isn't a websocket url", events_url));
+        }
+        let socket = (parsed.host, parsed.port);
+        let Ok(endpoint) = events_url.parse() else {
+            return Err(format!("the event websocket url the api gave, {:?}, isn't a valid url", events_url));
         };
-        let Ok((tls_stream, _response)) = tungstenite::client_tls(tls_request, tcp_stream) else {
-            return Err("failed to start tls session with event websocket".to_string());
+        // a ws:// endpoint gets a plain connection, wss:// gets tls.
+        let tls_request = ClientRequestBuilder::new(endpoint);
+        let tcp_stream = match http::connect_tcp((&socket.0, socket.1), &Timeouts::default()) {
+            Ok(tcp_stream) => tcp_stream,
+            Err(what_happened) => {
+                return Err(format!("{}\nfailed to start tcp session with event websocket", what_happened));
+            }
         };
-        tls_stream
+        match tungstenite::client_tls(tls_request, tcp_stream) {
+            Ok((tls_stream, _response)) => tls_stream,
+            // the handshake only stops partway when a read or write times out.
+            Err(HandshakeError::Interrupted(_)) => {
+                return Err("timed out starting tls session with event websocket".to_string());
+            },
+            Err(HandshakeError::Failure(_)) => {
+                return Err("failed to start tls session with event websocket".to_string());
+            }
+        }
     };
 
     authenticate(&mut stream)?;
@@ -76,49 +115,228 @@ fn start_ws_stream() -> Result<WebSocket<MaybeTlsStream<TcpStream>>, String> {
     Ok(stream)
 }
 
-fn handle_message(message: &HashMap<String, Value>, alarm_heap: &Arc<Mutex<AlarmHeap>>) -> Result<(), String> {
+// what's been found out about users the cache doesn't know, so they're only looked up once.
+enum Lookup {
+    IsBot(bool),
+    // asking the api failed, so they're treated as a bot until it's worth asking again.
+    Failed(Instant)
+}
+
+// how long to wait before asking about a user again, after it failed.
+const RETRY_LOOKUP_AFTER: Duration = Duration::from_secs(5 * 60);
+
+static BOT_USERS: Mutex<Option<HashMap<String, Lookup>>> = Mutex::new(None);
+
+// whether the user is a bot, or None if they need looking up.
+fn known_bot(user_id: &str) -> Option<bool> {
+    if let Some(user) = cache::user(user_id) {
+        return Some(user.is_bot);
+    }
+    let bot_users = BOT_USERS.lock().ok()?;
+    match bot_users.as_ref()?.get(user_id)? {
+        Lookup::IsBot(is_bot) => Some(*is_bot),
+        Lookup::Failed(when) if when.elapsed() < RETRY_LOOKUP_AFTER => Some(true),
+        Lookup::Failed(_) => None
+    }
+}
+
+// asks the api whether the user is a bot.  if it can't be found out, they're assumed to be one,
+// since answering a bot could start a loop but ignoring a person only loses one command.
+fn look_up_bot(user_id: &str) -> bool {
+    if let Some(is_bot) = known_bot(user_id) {
+        return is_bot;
+    }
+    let lookup = match stoat_api::wait_if_rate_limited(|| stoat_api::fetch_user(user_id)) {
+        Ok(user) => Lookup::IsBot(user.is_bot),
+        Err(what_happened) => {
+            println!("event listener: {}\nfailed to find out if {} is a bot, assuming they are", what_happened, user_id);
+            Lookup::Failed(Instant::now())
+        }
+    };
+    let is_bot = !matches!(lookup, Lookup::IsBot(false));
+    if let Ok(mut bot_users) = BOT_USERS.lock() {
+        bot_users.get_or_insert_default().insert(user_id.to_string(), lookup);
+    }
+    is_bot
+}
+
+// why a message shouldn't be answered, if it shouldn't.
+// anything a bot says is ignored (unless it's allowed to give commands),
+// so the bot can't end up in a loop with another bot, or with itself.
+fn ignore_reason(message: &HashMap<String, Value>, bot_id: &str, allowed_bots: &[&str], is_bot: impl Fn(&str) -> bool) -> Option<&'static str> {
+    // like "so-and-so joined the server".
+    if message.get("system").is_some_and(|system| *system != Value::Null) {
+        return Some("it's a system message");
+    }
+    let Some(Value::String(author)) = message.get("author") else {
+        return Some("it has no author");
+    };
+    if author == bot_id {
+        return Some("the bot sent it");
+    }
+    if allowed_bots.contains(&author.as_str()) {
+        return None;
+    }
+    // masquerading shows a message under someone else's name,
+    // which is how bridges and other bots post on people's behalf.
+    if message.get("masquerade").is_some_and(|masquerade| *masquerade != Value::Null) {
+        return Some("it's masqueraded");
+    }
+    if is_bot(author) {
+        return Some("a bot sent it");
+    }
+    None
+}
+
+fn answer(message: &HashMap<String, Value>, bot_id: &str, alarm_heap: &Arc<Mutex<AlarmHeap>>, is_bot: impl Fn(&str) -> bool) {
+    if let Some(reason) = ignore_reason(message, bot_id, config::ALLOWED_BOTS, is_bot) {
+        if let Some(Value::String(message_id)) = message.get("_id") {
+            println!("event listener: ignoring message {}, {}", message_id, reason);
+        }
+        return;
+    }
+    commands::dispatch(message, alarm_heap);
+}
+
+fn handle_message(message: &HashMap<String, Value>, bot_id: &str, alarm_heap: &Arc<Mutex<AlarmHeap>>) -> Result<(), String> {
     let Some(Value::Array(mentions)) = message.get("mentions") else {
         return Ok(());
     };
-    if !mentions.contains(&Value::String(config::BOT_ID.to_string())) {
+    if !mentions.contains(&Value::String(bot_id.to_string())) {
         return Ok(());
     }
-
-    if let Some(alarm) = Alarm::from_message(message) {
-        if let Err(what_happened) = file::save(&alarm) {
-            println!("failed to save alarm: {}", what_happened);
+    // so that if the bot stops partway through, it won't do this again when it catches up.
+    last_seen::flush()?;
+    match message.get("author") {
+        Some(Value::String(author)) if known_bot(author).is_none() => queue_lookup(author, message, bot_id, alarm_heap),
+        _ => {
+            answer(message, bot_id, alarm_heap, |user_id| known_bot(user_id) != Some(false));
+            Ok(())
         }
-        const GREEN_CHECK_BOX: &str = "%E2%9C%85";
-        if let Err(what_happened) = stoat_api::react(&alarm.channel_id, &alarm.message_id, GREEN_CHECK_BOX) {
-            println!("event listener: {}\nfailed to react {:?}", what_happened, &alarm);
+    }
+}
+
+// looking authors up can take a while, so it's done on a thread of its own
+// rather than holding up every other event behind it.
+// that one thread does all the lookups, one user at a time, and each user's only
+// queued once however many of their messages are waiting on them.
+struct LookupQueue {
+    sender: Sender<String>,
+    // the messages waiting on each user being looked up.
+    waiting: HashMap<String, Vec<HashMap<String, Value>>>
+}
+
+static LOOKUP_QUEUE: Mutex<Option<LookupQueue>> = Mutex::new(None);
+
+fn start_lookups(bot_id: String, alarm_heap: Arc<Mutex<AlarmHeap>>) -> LookupQueue {
+    let (sender, receiver) = mpsc::channel::<String>();
+    thread::spawn(move || {
+        for user_id in receiver {
+            look_up_bot(&user_id);
+            let waiting = LOOKUP_QUEUE.lock().ok()
+                .and_then(|mut lookup_queue| lookup_queue.as_mut()?.waiting.remove(&user_id))
+                .unwrap_or_default();
+            for message in &waiting {
+                answer(message, &bot_id, &alarm_heap, |user_id| known_bot(user_id) != Some(false));
+            }
         }
-        let mut heap_lock = alarm_heap.lock().expect("alarm heap mutex has been poisoned.  ending event listener.");
-        heap_lock.push(alarm);
+    });
+    LookupQueue { sender, waiting: HashMap::new() }
+}
+
+// answers the message once its author's been looked up.
+fn queue_lookup(author: &str, message: &HashMap<String, Value>, bot_id: &str, alarm_heap: &Arc<Mutex<AlarmHeap>>) -> Result<(), String> {
+    let Ok(mut lookup_queue) = LOOKUP_QUEUE.lock() else {
+        return Err("lookup queue mutex has been poisoned".to_string());
+    };
+    let lookup_queue = lookup_queue.get_or_insert_with(|| start_lookups(bot_id.to_string(), alarm_heap.clone()));
+    let waiting = lookup_queue.waiting.entry(author.to_string()).or_default();
+    waiting.push(message.clone());
+    // they're already queued, and this'll be answered along with the rest.
+    if waiting.len() > 1 {
         return Ok(());
     }
+    let Ok(()) = lookup_queue.sender.send(author.to_string()) else {
+        return Err("the lookup thread has stopped".to_string());
+    };
+    Ok(())
+}
 
-    let Some(Value::String(content)) = message.get("content") else {
+// handles the message, unless it's been seen before.
+fn handle_new_message(message: &HashMap<String, Value>, bot_id: &str, alarm_heap: &Arc<Mutex<AlarmHeap>>) -> Result<(), String> {
+    if let Some(Value::String(channel_id)) = message.get("channel")
+        && let Some(Value::String(message_id)) = message.get("_id")
+        && !last_seen::claim(channel_id, message_id)?
+    {
         return Ok(());
+    }
+    handle_message(message, bot_id, alarm_heap)
+}
+
+// goes through the messages sent in every channel the bot knows of since it last saw them,
+// so alarms set while it was offline or reconnecting aren't lost.
+fn catch_up(bot_id: &str, alarm_heap: &Arc<Mutex<AlarmHeap>>) {
+    const PAGE_SIZE: usize = 100;
+    // so a channel that's been busy for a long time can't hold everything else up forever.
+    const MOST_PAGES: usize = 10;
+    // this happens before listening, so no heartbeats go out until it's done.
+    // it stops well before one's overdue, and whatever it hasn't got to is skipped.
+    const CATCH_UP_FOR: Duration = Duration::from_secs(10);
+    let deadline = Instant::now() + CATCH_UP_FOR;
+    let out_of_time = || println!("event listener: ran out of time catching up on missed messages, skipping the rest");
+    let last_seen = match last_seen::all() {
+        Ok(last_seen) => last_seen,
+        Err(what_happened) => {
+            println!("event listener: {}\nfailed to catch up on missed messages", what_happened);
+            return;
+        }
     };
-    if
-        content.to_lowercase().contains("license")
-        && let Some(Value::String(channel_id)) = message.get("channel")
-    {
-        const AGPL3_MESSAGE: &str = "that's quite alarming is licensed under the gnu affero general public license version 3.  source code can be found at <https://github.com/shocktail39/thats-quite-alarming/>";
-        if let Err(what_happened) = stoat_api::post_message(channel_id, AGPL3_MESSAGE) {
-            println!("event listener: {}\nfailed to post license to {}", what_happened, channel_id);
+    for (channel_id, mut after) in last_seen {
+        for _ in 0..MOST_PAGES {
+            if Instant::now() >= deadline {
+                out_of_time();
+                return;
+            }
+            let fetched = loop {
+                match stoat_api::fetch_messages_after(&channel_id, &after, PAGE_SIZE) {
+                    Err(http::Error::RateLimited(wait)) if Instant::now() + wait < deadline => thread::sleep(wait),
+                    fetched => break fetched
+                }
+            };
+            let messages = match fetched {
+                Ok(messages) => messages,
+                Err(http::Error::RateLimited(_)) => {
+                    out_of_time();
+                    return;
+                },
+                Err(what_happened) => {
+                    println!("event listener: {}\nfailed to catch up on missed messages in {}", what_happened, channel_id);
+                    break;
+                }
+            };
+            for message in &messages {
+                if let Err(what_happened) = handle_new_message(message, bot_id, alarm_heap) {
+                    println!("event listener: {}", what_happened);
+                }
+                if let Some(Value::String(message_id)) = message.get("_id") {
+                    after = message_id.to_string();
+                }
+            }
+            if messages.len() < PAGE_SIZE {
+                break;
+            }
         }
     }
-    Ok(())
 }
 
-fn handle_event(event: &HashMap<String, Value>, alarm_heap: &Arc<Mutex<AlarmHeap>>) -> Result<(), String> {
+fn handle_event(event: &HashMap<String, Value>, bot_id: &str, alarm_heap: &Arc<Mutex<AlarmHeap>>) -> Result<(), String> {
     let Some(Value::String(msg_type)) = event.get("type") else {
         return Err("warning: no message type".to_string());
     };
+    cache::apply(event);
     match msg_type.as_str() {
         "Message" => {
-            handle_message(&event, &alarm_heap)?;
+            handle_new_message(event, bot_id, alarm_heap)?;
         },
         "Bulk" => {
             let Some(Value::Array(bulk_events)) = event.get("v") else {
@@ -128,7 +346,7 @@ fn handle_event(event: &HashMap<String, Value>, alarm_heap: &Arc<Mutex<AlarmHeap
                 let Value::Object(bulk_event) = bulk_event else {
                     continue;
                 };
-                if let Err(what_happened) = handle_event(bulk_event, &alarm_heap) {
+                if let Err(what_happened) = handle_event(bulk_event, bot_id, alarm_heap) {
                     println!("warning: error in bulk event {}", what_happened);
                 }
             }
@@ -138,30 +356,209 @@ fn handle_event(event: &HashMap<String, Value>, alarm_heap: &Arc<Mutex<AlarmHeap
     Ok(())
 }
 
-fn listen(mut stream: WebSocket<MaybeTlsStream<TcpStream>>, alarm_heap: Arc<Mutex<AlarmHeap>>) {
+fn is_timeout(what_happened: &tungstenite::Error) -> bool {
+    let tungstenite::Error::Io(what_happened) = what_happened else {
+        return false;
+    };
+    matches!(what_happened.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// how often to send stoat a heartbeat.  if the last one still hasn't been answered
+// by the time the next one is due, the connection is taken to be dead.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+// how long a read waits before giving up, so heartbeats still go out when nothing's happening.
+const READ_POLL: Duration = Duration::from_secs(1);
+// heartbeats that take longer than this to be answered are logged.
+// the first one after connecting always is.
+const SLOW_HEARTBEAT: Duration = Duration::from_secs(1);
+
+// how long the last heartbeat took to be answered.
+static LATENCY: Mutex<Option<Duration>> = Mutex::new(None);
+
+pub fn latency() -> Option<Duration> {
+    LATENCY.lock().ok().and_then(|latency| *latency)
+}
+
+fn set_read_timeout(stream: &WebSocket<MaybeTlsStream<TcpStream>>, timeout: Duration) -> io::Result<()> {
+    match stream.get_ref() {
+        MaybeTlsStream::Plain(tcp_stream) => tcp_stream.set_read_timeout(Some(timeout)),
+        MaybeTlsStream::NativeTls(tls_stream) => tls_stream.get_ref().set_read_timeout(Some(timeout)),
+        _ => Ok(())
+    }
+}
+
+// a heartbeat that's been sent, but not answered yet.
+struct Heartbeat {
+    data: i64,
+    sent: Instant
+}
+
+// gives back why the connection ended.
+fn listen(stream: &mut WebSocket<MaybeTlsStream<TcpStream>>, bot_id: &str, alarm_heap: &Arc<Mutex<AlarmHeap>>) -> String {
+    if let Err(what_happened) = set_read_timeout(stream, READ_POLL) {
+        return format!("failed to set read timeout: {}", what_happened);
+    }
+    let mut next_heartbeat = Instant::now();
+    let mut unanswered: Option<Heartbeat> = None;
+    let mut heartbeats_sent = 0;
     loop {
-        let Ok(response) = stream.read() else {
-            println!("warning: unexpected response from event endpoint");
-            continue;
-        };
-        if let Message::Close(_) = response {
-            println!("stream closed by event endpoint");
-            return;
+        if next_heartbeat <= Instant::now() {
+            if unanswered.is_some() {
+                return format!("heartbeat went unanswered for {}s", HEARTBEAT_INTERVAL.as_secs());
+            }
+            heartbeats_sent += 1;
+            let ping = Value::Object(HashMap::from([
+                ("type".to_string(), Value::String("Ping".to_string())),
+                ("data".to_string(), Value::Number(IntOrFloat::Int(heartbeats_sent)))
+            ]));
+            if let Err(what_happened) = stream.send(Message::Text(json::stringify(&ping).into())) {
+                return format!("failed to send heartbeat: {}", what_happened);
+            }
+            unanswered = Some(Heartbeat {
+                data: heartbeats_sent,
+                sent: Instant::now()
+            });
+            next_heartbeat = Instant::now() + HEARTBEAT_INTERVAL;
         }
-        let Ok((Value::Object(response), _)) = json::parse_value(&response.into_data(), 0) else {
+
+        let response = match stream.read() {
+            Ok(response) => response,
+            // nothing's come in for a bit, which is fine.
+            Err(what_happened) if is_timeout(&what_happened) => {
+                continue;
+            },
+            // anything else means the connection's broken,
+            // and reading again would only fail again.
+            Err(what_happened) => {
+                return format!("failed to read from event endpoint: {}", what_happened);
+            }
+        };
+        let text = match response {
+            Message::Text(text) => text,
+            Message::Close(Some(close_frame)) => {
+                return format!("stream closed by event endpoint with code {}: {}", close_frame.code, close_frame.reason);
+            },
+            Message::Close(None) => {
+                return "stream closed by event endpoint".to_string();
+            },
+            // events are asked for as json, so they all come as text.
+            Message::Binary(_) => {
+                println!("warning: ignoring unexpected binary frame from event endpoint");
+                continue;
+            },
+            // tungstenite answers pings by itself, the next time the stream is read or written.
+            // pongs only come back from pings, which the bot doesn't send (it uses stoat's heartbeat instead).
+            // and raw frames only ever come up when writing.
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {
+                continue;
+            }
+        };
+        let Ok((Value::Object(response), _)) = json::parse_value(text.as_bytes(), 0) else {
             println!("warning: event endpoint response is unexpectedly not a json object.");
             continue;
         };
-        if let Err(what_happened) = handle_event(&response, &alarm_heap) {
+        if let Some(Value::String(msg_type)) = response.get("type")
+            && msg_type == "Pong"
+        {
+            if let Some(heartbeat) = &unanswered
+                && let Some(Value::Number(IntOrFloat::Int(data))) = response.get("data")
+                && *data == heartbeat.data
+            {
+                let round_trip = heartbeat.sent.elapsed();
+                if heartbeat.data == 1 || round_trip > SLOW_HEARTBEAT {
+                    println!("event listener: heartbeat answered in {}ms", round_trip.as_millis());
+                }
+                if let Ok(mut latency) = LATENCY.lock() {
+                    *latency = Some(round_trip);
+                }
+                unanswered = None;
+            }
+            continue;
+        }
+        if let Err(what_happened) = handle_event(&response, bot_id, alarm_heap) {
             println!("event listener: {}", what_happened);
         }
     }
 }
 
-pub fn start_listening(alarm_heap: Arc<Mutex<AlarmHeap>>) -> JoinHandle<Result<(), String>> {
-    thread::spawn(|| {
-        let ws = start_ws_stream()?;
-        listen(ws, alarm_heap);
-        Ok(())
+// how long to wait before reconnecting, after failing to stay connected this many times in a row.
+// a random part of it is taken off, so if lots of bots get knocked off at once
+// they don't all come back at the same moment.
+fn reconnect_delay(failures: u32) -> Duration {
+    const FIRST_DELAY: Duration = Duration::from_secs(1);
+    const LONGEST_DELAY: Duration = Duration::from_secs(5 * 60);
+    let delay = FIRST_DELAY.saturating_mul(2u32.saturating_pow(failures.saturating_sub(1))).min(LONGEST_DELAY);
+    // RandomState is seeded randomly, which is plenty random for this.
+    let random = RandomState::new().build_hasher().finish();
+    // somewhere between half and all of the delay.
+    delay.mul_f64(0.5 + (random % 1000) as f64 / 2000.0)
+}
+
+// keeps the event websocket connected for as long as the bot runs,
+// reconnecting (and authenticating again) whenever it drops.
+// the alarms keep going off in the meantime, since that's the main thread's job.
+// bot_id is the bot's own user id, so it knows when it's been mentioned.
+// events_url is where the api says the event websocket is.
+pub fn start_listening(bot_id: String, events_url: String, alarm_heap: Arc<Mutex<AlarmHeap>>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut failures = 0;
+        loop {
+            println!("event listener: connecting");
+            match start_ws_stream(&events_url) {
+                Ok(mut stream) => {
+                    println!("event listener: connected");
+                    failures = 0;
+                    catch_up(&bot_id, &alarm_heap);
+                    let reason = listen(&mut stream, &bot_id, &alarm_heap);
+                    match latency() {
+                        Some(latency) => println!("event listener: disconnected, {} (the last heartbeat was answered in {}ms)", reason, latency.as_millis()),
+                        None => println!("event listener: disconnected, {}", reason)
+                    }
+                },
+                Err(what_happened) => {
+                    println!("event listener: failed to connect, {}", what_happened);
+                }
+            }
+            failures += 1;
+            let delay = reconnect_delay(failures);
+            println!("event listener: reconnecting in {}ms", delay.as_millis());
+            thread::sleep(delay);
+        }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOT_ID: &str = "01J0000000000000000000B0TS";
+    const PERSON_ID: &str = "01J00000000000000000PERS0N";
+    const OTHER_BOT_ID: &str = "01J000000000000000000THB0T";
+
+    fn message(json_text: &str) -> HashMap<String, Value> {
+        let Ok((Value::Object(message), _)) = json::parse_value(json_text.as_bytes(), 0) else {
+            panic!("not a json object: {}", json_text);
+        };
+        message
+    }
+
+    fn reason(json_text: &str, allowed_bots: &[&str]) -> Option<&'static str> {
+        ignore_reason(&message(json_text), BOT_ID, allowed_bots, |user_id| user_id == OTHER_BOT_ID)
+    }
+
+    #[test]
+    fn ignored_messages() {
+        let from = |author: &str, extra: &str| format!(r#"{{"_id":"1","author":"{}","content":"in 1s hi"{}}}"#, author, extra);
+        assert_eq!(reason(&from(PERSON_ID, ""), &[]), None);
+        assert_eq!(reason(&from(PERSON_ID, r#","system":null,"masquerade":null"#), &[]), None);
+        assert_eq!(reason(&from(BOT_ID, ""), &[]), Some("the bot sent it"));
+        assert_eq!(reason(&from(OTHER_BOT_ID, ""), &[]), Some("a bot sent it"));
+        assert_eq!(reason(&from(OTHER_BOT_ID, ""), &[OTHER_BOT_ID]), None);
+        assert_eq!(reason(&from(PERSON_ID, r#","masquerade":{"name":"not a bot, honest"}"#), &[]), Some("it's masqueraded"));
+        assert_eq!(reason(&from(OTHER_BOT_ID, r#","masquerade":{"name":"bridged"}"#), &[OTHER_BOT_ID]), None);
+        assert_eq!(reason(&from("00000000000000000000000000", r#","system":{"type":"user_joined","id":"x"}"#), &[]), Some("it's a system message"));
+        // even an allowed bot can't be answered when it's the bot itself.
+        assert_eq!(reason(&from(BOT_ID, ""), &[BOT_ID]), Some("the bot sent it"));
+        assert_eq!(reason(r#"{"_id":"1","content":"in 1s hi"}"#, &[]), Some("it has no author"));
+    }
+}
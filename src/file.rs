@@ -1,129 +1,360 @@
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::DirEntry;
 use std::path::Path;
 
 use chrono::DateTime;
+use chrono::Utc;
 
 use crate::alarm::Alarm;
 use crate::alarm_heap::AlarmHeap;
 use crate::config;
+use crate::json;
+use crate::json::Value;
+use crate::store::LoadSummary;
+use crate::ulid;
 
 // the folder structure is ./<channel id>/<message id>
-// the file structure of <message id> is two lines:
+// each <message id> file is one json object made by Alarm::to_json,
+// with a "version" field saying which format it's in.
+//
+// older versions of the bot wrote two lines instead:
 // <unix timestamp>
 // <message>
+// those still load, and get rewritten in the json format as they do.
+//
+// files that can't be loaded at all get moved into QUARANTINE_DIR,
+// under the same <channel id>/<message id> layout.
+//
+// channel and message ids come from the event websocket,
+// so they're checked to be ulids before they go anywhere near a path.
+// otherwise a "../" in one could write or delete files outside WHERE_TO_SAVE.
+// a saved alarm's ids have to match the folder and file it's in, too,
+// or saving it again would write somewhere else.
 
-pub fn load() -> Result<AlarmHeap, String> {
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    InvalidId(String),
+    Io(String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidId(id) => write!(f, "refusing to use {:?} as a path, it isn't a valid id", id),
+            Self::Io(what_happened) => f.write_str(what_happened)
+        }
+    }
+}
+
+fn check_ids(alarm: &Alarm) -> Result<(), Error> {
+    if !ulid::is_valid(&alarm.channel_id) {
+        return Err(Error::InvalidId(alarm.channel_id.to_string()));
+    }
+    if !ulid::is_valid(&alarm.message_id) {
+        return Err(Error::InvalidId(alarm.message_id.to_string()));
+    }
+    Ok(())
+}
+
+fn parse_legacy(file_text: &str, channel_id: &str, message_id: &str) -> Option<Alarm> {
+    let (timestamp_text, what) = file_text.split_once("\n")?;
+    let unix_seconds = timestamp_text.parse().ok()?;
+    let when = DateTime::from_timestamp(unix_seconds, 0)?.naive_utc();
+    Some(Alarm {
+        when,
+        what: what.to_string(),
+        channel_id: channel_id.to_string(),
+        message_id: message_id.to_string(),
+        author: None,
+        created: None,
+        recurrence: None,
+        target: None,
+        attempts: 0,
+        reacted: false,
+        retry_at: None
+    })
+}
+
+// moves a file that can't be loaded out of the way,
+// so it stops being retried every startup but isn't lost either.
+fn quarantine(path: &Path, channel_dir: &DirEntry, reason: &str) -> Result<(), String> {
+    let quarantine_dir = Path::new(config::QUARANTINE_DIR).join(channel_dir.file_name());
+    if let Err(what_happened) = std::fs::create_dir_all(&quarantine_dir) {
+        return Err(format!("failed to create quarantine folder {}: {}", quarantine_dir.display(), what_happened));
+    }
+    let Some(file_name) = path.file_name() else {
+        return Err(format!("failed to quarantine {}: it has no file name", path.display()));
+    };
+    let mut destination = quarantine_dir.join(file_name);
+    if std::fs::exists(&destination).ok().is_none_or(|exists| exists) {
+        // something with the same name was quarantined before,
+        // so don't overwrite it.
+        let mut unique_name = file_name.to_os_string();
+        unique_name.push(format!(".{}", Utc::now().timestamp_millis()));
+        destination = quarantine_dir.join(unique_name);
+    }
+    if let Err(what_happened) = std::fs::rename(path, &destination) {
+        return Err(format!("failed to quarantine {}: {}", path.display(), what_happened));
+    }
+    println!("quarantined {} to {}: {}", path.display(), destination.display(), reason);
+    Ok(())
+}
+
+fn from_saved(saved: &HashMap<String, Value>, channel_id: &str, message_id: &str) -> Result<Alarm, String> {
+    let alarm = Alarm::from_json(saved)?;
+    if alarm.channel_id != channel_id || alarm.message_id != message_id {
+        return Err(format!("it says it's {:?}/{:?}, which isn't where it's saved", alarm.channel_id, alarm.message_id));
+    }
+    Ok(alarm)
+}
+
+fn read_alarm_file(alarm_file: &DirEntry, channel_id: &str, message_id: &str, tidy_up: bool, summary: &mut LoadSummary) -> Result<Alarm, String> {
+    let file_bytes = match std::fs::read(alarm_file.path()) {
+        Ok(file_bytes) => file_bytes,
+        Err(what_happened) => {
+            return Err(format!("failed to read it: {}", what_happened));
+        }
+    };
+    let Ok(file_text) = String::from_utf8(file_bytes) else {
+        return Err("it isn't valid utf8".to_string());
+    };
+    if let Ok((Value::Object(saved), _)) = json::parse_value(file_text.as_bytes(), 0) {
+        return from_saved(&saved, channel_id, message_id);
+    }
+    let Some(alarm) = parse_legacy(&file_text, channel_id, message_id) else {
+        return Err("it's neither json nor the legacy two line format".to_string());
+    };
+    if !tidy_up {
+        return Ok(alarm);
+    }
+    match save(&alarm) {
+        Ok(()) => {
+            summary.migrated += 1;
+        },
+        Err(what_happened) => {
+            println!("failed to migrate alarm file {}: {}", alarm_file.path().display(), what_happened);
+        }
+    }
+    Ok(alarm)
+}
+
+pub fn load() -> Result<(AlarmHeap, LoadSummary), String> {
+    read_saved(true)
+}
+
+pub fn read() -> Result<(AlarmHeap, LoadSummary), String> {
+    read_saved(false)
+}
+
+// with tidy_up, legacy files get rewritten as json and broken ones get quarantined.
+// without it, nothing is changed, and broken files are just skipped.
+fn read_saved(tidy_up: bool) -> Result<(AlarmHeap, LoadSummary), String> {
     let mut alarm_heap = AlarmHeap::default();
+    let mut summary = LoadSummary::default();
 
     let top_folder = Path::new(config::WHERE_TO_SAVE);
     if std::fs::exists(top_folder).ok().is_none_or(|exists| !exists) {
-        return Ok(alarm_heap);
+        return Ok((alarm_heap, summary));
     }
 
-    let Ok(top_dir_contents) = std::fs::read_dir(top_folder) else {
-        return Err("failed to read directory of saved alarms".to_string());
+    let top_dir_contents = match std::fs::read_dir(top_folder) {
+        Ok(top_dir_contents) => top_dir_contents,
+        Err(what_happened) => {
+            return Err(format!("failed to read directory of saved alarms {}: {}", top_folder.display(), what_happened));
+        }
     };
-    let channel_dirs = top_dir_contents.filter_map(|file: std::io::Result<DirEntry>| -> Option<DirEntry> {
-        let Ok(file) = file else {
-            return None;
-        };
-        let Ok(file_type) = file.file_type() else {
-            return None;
+    for channel_dir in top_dir_contents {
+        let channel_dir = match channel_dir {
+            Ok(channel_dir) => channel_dir,
+            Err(what_happened) => {
+                return Err(format!("failed to list {}: {}", top_folder.display(), what_happened));
+            }
         };
-        if file_type.is_dir() {
-            Some(file)
-        } else {
-            None
+        let channel_path = channel_dir.path();
+        if !channel_dir.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            println!("skipping {}: not a channel folder", channel_path.display());
+            summary.skipped += 1;
+            continue;
         }
-    });
-
-    for channel_dir in channel_dirs {
-        let Ok(this_channels_dir) = std::fs::read_dir(channel_dir.path()) else {
-            return Err("failed to load alarms for channel".to_string());
+        let Some(channel_id) = channel_dir.file_name().to_str().filter(|channel_id| ulid::is_valid(channel_id)).map(str::to_string) else {
+            println!("skipping {}: folder name isn't a channel id", channel_path.display());
+            summary.skipped += 1;
+            continue;
         };
-        let this_channels_alarms = this_channels_dir.filter_map(|file| {
-            let Ok(file) = file else {
-                return None;
-            };
-            let Ok(file_type) = file.file_type() else {
-                return None;
-            };
-            if file_type.is_file() {
-                Some(file)
-            } else {
-                None
+
+        let this_channels_dir = match std::fs::read_dir(&channel_path) {
+            Ok(this_channels_dir) => this_channels_dir,
+            Err(what_happened) => {
+                return Err(format!("failed to load alarms for channel {}: {}", channel_id, what_happened));
             }
-        });
-        for alarm_file in this_channels_alarms {
-            let Ok(file_bytes) = std::fs::read(alarm_file.path()) else {
-                continue;
-            };
-            let Ok(file_text) = String::from_utf8(file_bytes) else {
-                continue;
-            };
-            let Some((timestamp_text, what)) = file_text.split_once("\n") else {
-                continue;
-            };
-            let Ok(unix_seconds) = timestamp_text.parse() else {
-                continue;
-            };
-            let Some(datetime) = DateTime::from_timestamp(unix_seconds, 0) else {
-                continue;
+        };
+        for alarm_file in this_channels_dir {
+            let alarm_file = match alarm_file {
+                Ok(alarm_file) => alarm_file,
+                Err(what_happened) => {
+                    return Err(format!("failed to list alarms for channel {}: {}", channel_id, what_happened));
+                }
             };
-            let when = datetime.naive_utc();
-            let Ok(message_id) = alarm_file.file_name().into_string() else {
+            let alarm_path = alarm_file.path();
+            if !alarm_file.file_type().is_ok_and(|file_type| file_type.is_file()) {
+                println!("skipping {}: not an alarm file", alarm_path.display());
+                summary.skipped += 1;
                 continue;
-            };
-            let Ok(channel_id) = channel_dir.file_name().into_string() else {
+            }
+            let Some(message_id) = alarm_file.file_name().to_str().filter(|message_id| ulid::is_valid(message_id)).map(str::to_string) else {
+                println!("skipping {}: file name isn't a message id", alarm_path.display());
+                summary.skipped += 1;
                 continue;
             };
-            let what = what.to_string();
-            alarm_heap.push(Alarm {
-                when,
-                what,
-                channel_id,
-                message_id
-            });
+
+            match read_alarm_file(&alarm_file, &channel_id, &message_id, tidy_up, &mut summary) {
+                Ok(alarm) => {
+                    alarm_heap.push(alarm);
+                    summary.loaded += 1;
+                },
+                Err(reason) if tidy_up => {
+                    quarantine(&alarm_path, &channel_dir, &reason)?;
+                    summary.quarantined += 1;
+                },
+                Err(reason) => {
+                    println!("skipping {}: {}", alarm_path.display(), reason);
+                    summary.skipped += 1;
+                }
+            }
         }
     }
 
-    return Ok(alarm_heap);
+    Ok((alarm_heap, summary))
 }
 
-pub fn save(alarm: &Alarm) -> Result<(), String> {
+pub fn save(alarm: &Alarm) -> Result<(), Error> {
+    check_ids(alarm)?;
     let top_folder = Path::new(config::WHERE_TO_SAVE);
-    if std::fs::exists(&top_folder).ok().is_none_or(|exists| !exists) {
-        let Ok(()) = std::fs::create_dir_all(&top_folder) else {
-            return Err("failed to create top folder".to_string());
+    if std::fs::exists(top_folder).ok().is_none_or(|exists| !exists) {
+        let Ok(()) = std::fs::create_dir_all(top_folder) else {
+            return Err(Error::Io("failed to create top folder".to_string()));
         };
     }
     let channel_dir = top_folder.join(&alarm.channel_id);
     if std::fs::exists(&channel_dir).ok().is_none_or(|exists| !exists) {
         let Ok(()) = std::fs::create_dir(&channel_dir) else {
-            return Err(format!("failed to create channel folder {}", &alarm.channel_id));
+            return Err(Error::Io(format!("failed to create channel folder {}", &alarm.channel_id)));
         };
     }
     let message_file = channel_dir.join(&alarm.message_id);
-    let timestamp = alarm.when.and_utc().timestamp().to_string();
-    let file_contents = format!("{}\n{}", timestamp, alarm.what);
+    let file_contents = json::stringify(&alarm.to_json());
     let Ok(()) = std::fs::write(message_file, file_contents) else {
-        return Err(format!("failed to write alarm file {}", &alarm.message_id));
+        return Err(Error::Io(format!("failed to write alarm file {}", &alarm.message_id)));
     };
     Ok(())
 }
 
-pub fn delete(alarm: &Alarm) -> Result<(), String> {
+pub fn delete(alarm: &Alarm) -> Result<(), Error> {
+    check_ids(alarm)?;
     let top_folder = Path::new(config::WHERE_TO_SAVE);
-    if std::fs::exists(&top_folder).ok().is_none_or(|exists| !exists) {
-        return Err("delete could not access top folder".to_string());
+    if std::fs::exists(top_folder).ok().is_none_or(|exists| !exists) {
+        return Err(Error::Io("delete could not access top folder".to_string()));
     }
     let channel_dir = top_folder.join(&alarm.channel_id);
     if std::fs::exists(&channel_dir).ok().is_none_or(|exists| !exists) {
-        return Err(format!("delete could not access channel folder {}", &alarm.channel_id));
+        return Err(Error::Io(format!("delete could not access channel folder {}", &alarm.channel_id)));
     }
     let message_file = channel_dir.join(&alarm.message_id);
-    let Ok(()) = std::fs::remove_file(message_file) else{
-        return Err(format!("failed to delete alarm file {}", &alarm.message_id));
+    let Ok(()) = std::fs::remove_file(message_file) else {
+        return Err(Error::Io(format!("failed to delete alarm file {}", &alarm.message_id)));
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL_ID: &str = "01ARZ3NDEKTSV4RRFFQ69G5FAV";
+    const MESSAGE_ID: &str = "01BX5ZZKBKACTAV9WEVGEMMVRZ";
+
+    fn alarm_with_ids(channel_id: &str, message_id: &str) -> Alarm {
+        Alarm {
+            when: DateTime::from_timestamp(0, 0).unwrap().naive_utc(),
+            what: "test".to_string(),
+            channel_id: channel_id.to_string(),
+            message_id: message_id.to_string(),
+            author: None,
+            created: None,
+            recurrence: None,
+            target: None,
+            attempts: 0,
+            reacted: false,
+            retry_at: None
+        }
+    }
+
+    fn assert_rejected(channel_id: &str, message_id: &str, bad_id: &str) {
+        let alarm = alarm_with_ids(channel_id, message_id);
+        assert_eq!(save(&alarm), Err(Error::InvalidId(bad_id.to_string())));
+        assert_eq!(delete(&alarm), Err(Error::InvalidId(bad_id.to_string())));
+    }
+
+    #[test]
+    fn migrates_legacy_files() {
+        let alarm = parse_legacy("1800000000\nwake up\nand smell the coffee", CHANNEL_ID, MESSAGE_ID).unwrap();
+        assert_eq!(alarm.when, DateTime::from_timestamp(1_800_000_000, 0).unwrap().naive_utc());
+        assert_eq!(alarm.what, "wake up\nand smell the coffee");
+        assert_eq!(alarm.channel_id, CHANNEL_ID);
+        assert_eq!(alarm.message_id, MESSAGE_ID);
+
+        // what gets written back reads the same as what was migrated.
+        let Ok((Value::Object(saved), _)) = json::parse_value(json::stringify(&alarm.to_json()).as_bytes(), 0) else {
+            panic!("migrated alarm isn't a json object");
+        };
+        assert_eq!(format!("{:?}", Alarm::from_json(&saved).unwrap()), format!("{:?}", alarm));
+
+        assert!(parse_legacy("just one line", CHANNEL_ID, MESSAGE_ID).is_none());
+        assert!(parse_legacy("soon\nwake up", CHANNEL_ID, MESSAGE_ID).is_none());
+    }
+
+    #[test]
+    fn ids_have_to_match_where_its_saved() {
+        let saved = |alarm: &Alarm| {
+            let Value::Object(saved) = alarm.to_json() else {
+                panic!("alarm json isn't an object");
+            };
+            saved
+        };
+        let alarm = alarm_with_ids(CHANNEL_ID, MESSAGE_ID);
+        assert_eq!(from_saved(&saved(&alarm), CHANNEL_ID, MESSAGE_ID).unwrap().message_id, MESSAGE_ID);
+        assert!(from_saved(&saved(&alarm), CHANNEL_ID, "01BX5ZZKBKACTAV9WEVGEMMVR0").is_err());
+        assert!(from_saved(&saved(&alarm), "01BX5ZZKBKACTAV9WEVGEMMVR0", MESSAGE_ID).is_err());
+        let escaping = alarm_with_ids(CHANNEL_ID, "../../etc/passwd");
+        assert!(from_saved(&saved(&escaping), CHANNEL_ID, MESSAGE_ID).is_err());
+    }
+
+    #[test]
+    fn parent_directory() {
+        assert_rejected("..", MESSAGE_ID, "..");
+        assert_rejected("../", MESSAGE_ID, "../");
+        assert_rejected(CHANNEL_ID, "../../etc/passwd", "../../etc/passwd");
+        assert_rejected(CHANNEL_ID, "..\\..\\boot.ini", "..\\..\\boot.ini");
+    }
+
+    #[test]
+    fn absolute_path() {
+        assert_rejected("/tmp", MESSAGE_ID, "/tmp");
+        assert_rejected(CHANNEL_ID, "/etc/passwd", "/etc/passwd");
+        // the right length, so only the slash gives it away.
+        assert_rejected(CHANNEL_ID, "/1ARZ3NDEKTSV4RRFFQ69G5FAV", "/1ARZ3NDEKTSV4RRFFQ69G5FAV");
+    }
+
+    #[test]
+    fn nul_byte() {
+        assert_rejected("01ARZ3NDEKTSV4RRFFQ69G5FA\0", MESSAGE_ID, "01ARZ3NDEKTSV4RRFFQ69G5FA\0");
+        assert_rejected(CHANNEL_ID, "\0", "\0");
+    }
+
+    #[test]
+    fn empty() {
+        assert_rejected("", MESSAGE_ID, "");
+        assert_rejected(CHANNEL_ID, "", "");
+    }
+}
@@ -0,0 +1,286 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::NaiveDateTime;
+use chrono::Utc;
+
+use crate::alarm::Alarm;
+use crate::config;
+use crate::json;
+use crate::json::IntOrFloat;
+use crate::json::Value;
+use crate::stoat_api::Message;
+
+// every alarm that goes off is archived in HISTORY_FILE, one json object per line:
+// {"alarm":<the alarm, from Alarm::to_json>,"fired":<unix millis>,"status":"delivered"|"failed","error":<string or null>,"posted":<message id or null>}
+// entries older than HISTORY_RETENTION_DAYS are pruned every so often.
+
+// keeps the main loop's appends and the listener's reads from overlapping.
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug)]
+pub enum Status {
+    Delivered,
+    Failed(String)
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub alarm: Alarm,
+    pub fired: NaiveDateTime,
+    pub status: Status,
+    // the message the bot posted when the alarm went off, if it got posted.
+    pub posted: Option<String>
+}
+
+impl Entry {
+    fn to_json(&self) -> Value {
+        let (status, error) = match &self.status {
+            Status::Delivered => ("delivered", Value::Null),
+            Status::Failed(what_happened) => ("failed", Value::String(what_happened.to_string()))
+        };
+        Value::Object(HashMap::from([
+            ("alarm".to_string(), self.alarm.to_json()),
+            ("fired".to_string(), Value::Number(IntOrFloat::Int(self.fired.and_utc().timestamp_millis()))),
+            ("status".to_string(), Value::String(status.to_string())),
+            ("error".to_string(), error),
+            ("posted".to_string(), match &self.posted {
+                Some(posted) => Value::String(posted.to_string()),
+                None => Value::Null
+            })
+        ]))
+    }
+
+    fn from_json(saved: &HashMap<String, Value>) -> Option<Self> {
+        let Some(Value::Object(alarm)) = saved.get("alarm") else {
+            return None;
+        };
+        let alarm = Alarm::from_json(alarm).ok()?;
+        let Some(Value::Number(fired)) = saved.get("fired") else {
+            return None;
+        };
+        let fired = DateTime::from_timestamp_millis(fired.as_int())?.naive_utc();
+        let status = match (saved.get("status"), saved.get("error")) {
+            (Some(Value::String(status)), _) if status == "delivered" => Status::Delivered,
+            (Some(Value::String(status)), Some(Value::String(what_happened))) if status == "failed" => Status::Failed(what_happened.to_string()),
+            _ => {
+                return None;
+            }
+        };
+        let posted = match saved.get("posted") {
+            Some(Value::String(posted)) => Some(posted.to_string()),
+            _ => None
+        };
+        Some(Self {
+            alarm,
+            fired,
+            status,
+            posted
+        })
+    }
+}
+
+// these take the path so the tests can use their own file.
+// they must be called with HISTORY_LOCK locked.
+
+fn read_entries(path: &Path) -> Result<Vec<Entry>, String> {
+    if std::fs::exists(path).ok().is_none_or(|exists| !exists) {
+        return Ok(vec![]);
+    }
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(what_happened) => {
+            return Err(format!("failed to read history {}: {}", path.display(), what_happened));
+        }
+    };
+    let mut entries = vec![];
+    for line in contents.split(|byte| *byte == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let Ok((Value::Object(saved), _)) = json::parse_value(line, 0) else {
+            println!("history: skipping an entry that isn't a json object");
+            continue;
+        };
+        let Some(entry) = Entry::from_json(&saved) else {
+            println!("history: skipping a malformed entry");
+            continue;
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn append(path: &Path, entry: &Entry) -> Result<(), String> {
+    let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) else {
+        return Err(format!("failed to open history {}", path.display()));
+    };
+    let Ok(()) = file.write_all(format!("{}\n", json::stringify(&entry.to_json())).as_bytes()) else {
+        return Err(format!("failed to append to history {}", path.display()));
+    };
+    Ok(())
+}
+
+fn recent_in(path: &Path, author: &str, channel_id: &str, limit: usize) -> Result<Vec<Entry>, String> {
+    let mut entries: Vec<Entry> = read_entries(path)?
+        .into_iter()
+        .filter(|entry| entry.alarm.author.as_deref() == Some(author) && entry.alarm.channel_id == channel_id)
+        .collect();
+    entries.sort_by_key(|entry| Reverse(entry.fired));
+    entries.truncate(limit);
+    Ok(entries)
+}
+
+// gives back how many entries were dropped.
+fn prune_before(path: &Path, cutoff: NaiveDateTime) -> Result<usize, String> {
+    let entries = read_entries(path)?;
+    let kept: Vec<&Entry> = entries.iter().filter(|entry| entry.fired >= cutoff).collect();
+    if kept.len() == entries.len() {
+        return Ok(0);
+    }
+
+    let mut contents = String::new();
+    for entry in &kept {
+        contents.push_str(&json::stringify(&entry.to_json()));
+        contents.push('\n');
+    }
+    // written to the side and renamed over, so a crash can't lose the whole history.
+    let temp_path = format!("{}.tmp", path.display());
+    if let Err(what_happened) = std::fs::write(&temp_path, contents) {
+        return Err(format!("failed to write pruned history {}: {}", temp_path, what_happened));
+    }
+    if let Err(what_happened) = std::fs::rename(&temp_path, path) {
+        return Err(format!("failed to replace history with pruned history: {}", what_happened));
+    }
+    Ok(entries.len() - kept.len())
+}
+
+pub fn record(alarm: &Alarm, status: Status, posted: Option<&Message>) -> Result<(), String> {
+    let entry = Entry {
+        alarm: alarm.clone(),
+        fired: Utc::now().naive_utc(),
+        status,
+        posted: posted.map(|posted| posted.id.to_string())
+    };
+    let Ok(_history_lock) = HISTORY_LOCK.lock() else {
+        return Err("history mutex has been poisoned".to_string());
+    };
+    append(Path::new(config::HISTORY_FILE), &entry)
+}
+
+// the most recent entries for alarms set by author in the channel, newest first.
+// only the channel's, so asking in one channel can't show what was set in another (or in a dm).
+pub fn recent_for(author: &str, channel_id: &str, limit: usize) -> Result<Vec<Entry>, String> {
+    let Ok(_history_lock) = HISTORY_LOCK.lock() else {
+        return Err("history mutex has been poisoned".to_string());
+    };
+    recent_in(Path::new(config::HISTORY_FILE), author, channel_id, limit)
+}
+
+// drops every entry that fired more than HISTORY_RETENTION_DAYS ago.
+pub fn prune() -> Result<(), String> {
+    let Ok(_history_lock) = HISTORY_LOCK.lock() else {
+        return Err("history mutex has been poisoned".to_string());
+    };
+    let cutoff = Utc::now().naive_utc() - Duration::from_secs(config::HISTORY_RETENTION_DAYS * 24 * 60 * 60);
+    let pruned = prune_before(Path::new(config::HISTORY_FILE), cutoff)?;
+    if pruned > 0 {
+        println!("history: pruned {} entries", pruned);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHANNEL_ID: &str = "01ARZ3NDEKTSV4RRFFQ69G5FAV";
+    const DM_ID: &str = "01BX5ZZKBKACTAV9WEVGEMMVRZ";
+    const AUTHOR_ID: &str = "01J0000000000000000000ATHR";
+
+    fn test_file(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("thats-quite-alarming-history-{}-{}.jsonl", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn entry(what: &str, channel_id: &str, author: &str, fired_millis: i64) -> Entry {
+        Entry {
+            alarm: Alarm {
+                when: DateTime::from_timestamp_millis(fired_millis).unwrap().naive_utc(),
+                what: what.to_string(),
+                channel_id: channel_id.to_string(),
+                message_id: "01BX5ZZKBKACTAV9WEVGEMMVS0".to_string(),
+                author: Some(author.to_string()),
+                created: None,
+                recurrence: None,
+                target: None,
+                attempts: 0,
+                reacted: false,
+                retry_at: None
+            },
+            fired: DateTime::from_timestamp_millis(fired_millis).unwrap().naive_utc(),
+            status: Status::Delivered,
+            posted: None
+        }
+    }
+
+    fn whats(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.alarm.what.as_str()).collect()
+    }
+
+    #[test]
+    fn records_and_reads_back() {
+        let path = test_file("records");
+        let mut failed = entry("failed", CHANNEL_ID, AUTHOR_ID, 3000);
+        failed.status = Status::Failed("kicked".to_string());
+        failed.posted = Some("01BX5ZZKBKACTAV9WEVGEMMVS1".to_string());
+        append(&path, &entry("first", CHANNEL_ID, AUTHOR_ID, 1000)).unwrap();
+        append(&path, &failed).unwrap();
+
+        let entries = read_entries(&path).unwrap();
+        assert_eq!(whats(&entries), ["first", "failed"]);
+        assert!(matches!(&entries[1].status, Status::Failed(what_happened) if what_happened == "kicked"));
+        assert_eq!(entries[1].posted.as_deref(), Some("01BX5ZZKBKACTAV9WEVGEMMVS1"));
+        assert_eq!(entries[1].fired, DateTime::from_timestamp_millis(3000).unwrap().naive_utc());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recent_only_shows_the_authors_alarms_in_the_channel() {
+        let path = test_file("recent");
+        append(&path, &entry("oldest", CHANNEL_ID, AUTHOR_ID, 1000)).unwrap();
+        append(&path, &entry("secret", DM_ID, AUTHOR_ID, 2000)).unwrap();
+        append(&path, &entry("someone else's", CHANNEL_ID, "01J0000000000000000000THER", 3000)).unwrap();
+        append(&path, &entry("newest", CHANNEL_ID, AUTHOR_ID, 5000)).unwrap();
+        append(&path, &entry("middle", CHANNEL_ID, AUTHOR_ID, 4000)).unwrap();
+        // a torn line from a crash is skipped, not fatal.
+        std::fs::write(&path, [std::fs::read(&path).unwrap(), b"{\"alarm\":".to_vec()].concat()).unwrap();
+
+        assert_eq!(whats(&recent_in(&path, AUTHOR_ID, CHANNEL_ID, 10).unwrap()), ["newest", "middle", "oldest"]);
+        assert_eq!(whats(&recent_in(&path, AUTHOR_ID, CHANNEL_ID, 2).unwrap()), ["newest", "middle"]);
+        assert_eq!(whats(&recent_in(&path, AUTHOR_ID, DM_ID, 10).unwrap()), ["secret"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn prunes_old_entries() {
+        let path = test_file("prune");
+        append(&path, &entry("old", CHANNEL_ID, AUTHOR_ID, 1000)).unwrap();
+        append(&path, &entry("new", CHANNEL_ID, AUTHOR_ID, 5000)).unwrap();
+
+        let cutoff = DateTime::from_timestamp_millis(2000).unwrap().naive_utc();
+        assert_eq!(prune_before(&path, cutoff), Ok(1));
+        assert_eq!(whats(&read_entries(&path).unwrap()), ["new"]);
+        // nothing left to prune, so the file isn't rewritten.
+        assert_eq!(prune_before(&path, cutoff), Ok(0));
+        assert_eq!(prune_before(&test_file("missing"), cutoff), Ok(0));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
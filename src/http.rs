@@ -0,0 +1,706 @@
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use native_tls::HandshakeError;
+use native_tls::TlsConnector;
+use native_tls::TlsStream;
+
+use crate::config;
+
+// a small http/1.1 client, just enough for the stoat api.
+// connections are kept alive and reused, since setting up tls
+// for every single request is slow.
+// plain http works too, for talking to a server on localhost.
+// responses can be sized by content-length, chunked, or end when the connection closes.
+// every connect, read and write has a timeout, so a server that stops answering
+// without hanging up turns into an error instead of blocking forever.
+
+// how many idle connections to keep around per host.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+// where requests get sent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Server {
+    // the name used for tls and the host header.
+    pub domain: String,
+    // where to actually connect to.
+    pub address: (String, u16),
+    // false for plain http.
+    pub tls: bool
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>)
+}
+
+impl Read for Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buffer),
+            Self::Tls(stream) => stream.read(buffer)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buffer),
+            Self::Tls(stream) => stream.write(buffer)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush()
+        }
+    }
+}
+
+struct Connection {
+    server: Server,
+    stream: BufReader<Stream>
+}
+
+static IDLE_CONNECTIONS: Mutex<Vec<Connection>> = Mutex::new(vec![]);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub read: Duration,
+    pub write: Duration
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: config::CONNECT_TIMEOUT,
+            read: config::READ_TIMEOUT,
+            write: config::WRITE_TIMEOUT
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    // header names are lowercased.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    // the request was never sent, because it wasn't valid.
+    InvalidRequest(String),
+    // couldn't connect, or the connection broke before the request was all sent,
+    // so the server can't have acted on it.
+    Connection(String),
+    // the request was sent, but the connection broke before the whole response came back,
+    // so the server may or may not have acted on it.
+    ConnectionLost(String),
+    // the server didn't accept the connection in time.
+    ConnectTimeout(String),
+    // the server stopped sending partway through the response (or never started).
+    ReadTimeout(String),
+    // the server stopped taking the request partway through.
+    WriteTimeout(String),
+    // the server sent back something that isn't http.
+    Malformed(String),
+    // 3xx.  nothing the bot talks to redirects, so these aren't followed.
+    Redirection(Response),
+    // 4xx, the request was wrong somehow.
+    Client(Response),
+    // 5xx, the server broke.
+    Server(Response),
+    // the request wasn't sent, or was refused with a 429,
+    // because of a rate limit.  it can be tried again after this long.
+    RateLimited(Duration),
+    // the request went through, but the body that came back
+    // isn't what the caller expected.  http itself never gives this back,
+    // it's for callers that read the body, like stoat_api.
+    UnexpectedBody(String)
+}
+
+impl Error {
+    // the status of the response that came with the error, if there was one.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Self::Redirection(response) | Self::Client(response) | Self::Server(response) => Some(response.status),
+            Self::InvalidRequest(_) | Self::Connection(_) | Self::ConnectionLost(_) | Self::ConnectTimeout(_) | Self::ReadTimeout(_) | Self::WriteTimeout(_)
+                | Self::Malformed(_) | Self::RateLimited(_) | Self::UnexpectedBody(_) => None
+        }
+    }
+
+    // whether sending the same request again could never work.
+    // that's only a 403 or 404 (missing permissions, deleted channel, ...),
+    // or a request that couldn't be sent at all.
+    // other 4xx, like a 400 or 401, are more likely the bot's fault than the request's,
+    // so they're retried like anything else.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::Client(response) => response.status == 403 || response.status == 404,
+            Self::InvalidRequest(_) => true,
+            Self::Connection(_) | Self::ConnectionLost(_) | Self::ConnectTimeout(_) | Self::ReadTimeout(_) | Self::WriteTimeout(_)
+                | Self::Malformed(_) | Self::Redirection(_) | Self::Server(_) | Self::RateLimited(_) | Self::UnexpectedBody(_) => false
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidRequest(what_happened) => write!(f, "http: not sending invalid request, {}", what_happened),
+            Self::Connection(what_happened) => write!(f, "http: {}", what_happened),
+            Self::ConnectionLost(what_happened) => write!(f, "http: request was sent, but {}", what_happened),
+            Self::ConnectTimeout(what_happened) => write!(f, "http: timed out connecting to {}", what_happened),
+            Self::ReadTimeout(what_happened) => write!(f, "http: timed out waiting for {}", what_happened),
+            Self::WriteTimeout(what_happened) => write!(f, "http: timed out sending {}", what_happened),
+            Self::Malformed(what_happened) => write!(f, "http: malformed response, {}", what_happened),
+            Self::Redirection(response) => write!(f, "http: unexpected redirect with status {}", response.status),
+            Self::Client(response) => write!(f, "http: request refused with status {}: {}", response.status, String::from_utf8_lossy(&response.body)),
+            Self::Server(response) => write!(f, "http: server error with status {}: {}", response.status, String::from_utf8_lossy(&response.body)),
+            Self::RateLimited(wait) => write!(f, "http: rate limited for another {}ms", wait.as_millis()),
+            Self::UnexpectedBody(what_happened) => write!(f, "http: request went through, but {}", what_happened)
+        }
+    }
+}
+
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub headers: &'a [(&'a str, &'a str)],
+    pub body: &'a [u8]
+}
+
+// a read or write that runs out of time fails with WouldBlock on unix
+// and TimedOut on windows.
+fn is_timeout(what_happened: &io::Error) -> bool {
+    matches!(what_happened.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+fn read_error(what_happened: io::Error, what: &str) -> Error {
+    if is_timeout(&what_happened) {
+        Error::ReadTimeout(what.to_string())
+    } else {
+        Error::ConnectionLost(format!("failed to read {}: {}", what, what_happened))
+    }
+}
+
+// connects over plain tcp, with timeouts set on the stream.
+// tries every address the host resolves to, since TcpStream::connect_timeout
+// only takes one, and keeps the last error if none of them work.
+pub fn connect_tcp((host, port): (&str, u16), timeouts: &Timeouts) -> Result<TcpStream, Error> {
+    let addresses = match (host, port).to_socket_addrs() {
+        Ok(addresses) => addresses,
+        Err(what_happened) => {
+            return Err(Error::Connection(format!("failed to look up {}:{}: {}", host, port, what_happened)));
+        }
+    };
+    let mut last_error = Error::Connection(format!("{}:{} has no addresses", host, port));
+    for address in addresses {
+        let tcp_stream = match TcpStream::connect_timeout(&address, timeouts.connect) {
+            Ok(tcp_stream) => tcp_stream,
+            Err(what_happened) => {
+                last_error = if is_timeout(&what_happened) {
+                    Error::ConnectTimeout(format!("{}:{}", host, port))
+                } else {
+                    Error::Connection(format!("failed to connect to {}:{}: {}", host, port, what_happened))
+                };
+                continue;
+            }
+        };
+        let configured = tcp_stream.set_read_timeout(Some(timeouts.read))
+            .and_then(|()| tcp_stream.set_write_timeout(Some(timeouts.write)));
+        if let Err(what_happened) = configured {
+            return Err(Error::Connection(format!("failed to set timeouts on connection to {}:{}: {}", host, port, what_happened)));
+        }
+        return Ok(tcp_stream);
+    }
+    Err(last_error)
+}
+
+fn connect(server: &Server, timeouts: &Timeouts) -> Result<Connection, Error> {
+    let (host, port) = &server.address;
+    let tcp_stream = connect_tcp((host, *port), timeouts)?;
+    if !server.tls {
+        return Ok(Connection {
+            server: server.clone(),
+            stream: BufReader::new(Stream::Plain(tcp_stream))
+        });
+    }
+    let domain = &server.domain;
+    let connector = match TlsConnector::new() {
+        Ok(connector) => connector,
+        Err(what_happened) => {
+            return Err(Error::Connection(format!("failed to create tls connector: {}", what_happened)));
+        }
+    };
+    let tls_stream = match connector.connect(domain, tcp_stream) {
+        Ok(tls_stream) => tls_stream,
+        // the handshake only stops partway when a read or write times out.
+        // it's part of connecting, so nothing's been sent yet.
+        Err(HandshakeError::WouldBlock(_)) => {
+            return Err(Error::ConnectTimeout(format!("{} (during the tls handshake)", domain)));
+        },
+        Err(what_happened) => {
+            return Err(Error::Connection(format!("failed to start tls with {}: {}", domain, what_happened)));
+        }
+    };
+    Ok(Connection {
+        server: server.clone(),
+        stream: BufReader::new(Stream::Tls(tls_stream))
+    })
+}
+
+fn take_idle_connection(server: &Server) -> Option<Connection> {
+    let mut idle_connections = IDLE_CONNECTIONS.lock().ok()?;
+    let index = idle_connections.iter().position(|connection| connection.server == *server)?;
+    Some(idle_connections.swap_remove(index))
+}
+
+fn return_idle_connection(connection: Connection) {
+    let Ok(mut idle_connections) = IDLE_CONNECTIONS.lock() else {
+        return;
+    };
+    let idle_for_host = idle_connections.iter()
+        .filter(|idle| idle.server == connection.server)
+        .count();
+    if idle_for_host < MAX_IDLE_CONNECTIONS {
+        idle_connections.push(connection);
+    }
+}
+
+fn read_line(stream: &mut impl BufRead) -> Result<String, Error> {
+    let mut line = vec![];
+    match stream.read_until(b'\n', &mut line) {
+        Ok(0) => Err(Error::ConnectionLost("connection closed before the response was finished".to_string())),
+        Ok(_) => {
+            let Some(line) = line.strip_suffix(b"\n") else {
+                return Err(Error::ConnectionLost("connection closed partway through a line".to_string()));
+            };
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let Ok(line) = String::from_utf8(line.to_vec()) else {
+                return Err(Error::Malformed("status line or header is not valid utf8".to_string()));
+            };
+            Ok(line)
+        },
+        Err(what_happened) => Err(read_error(what_happened, "response"))
+    }
+}
+
+// the biggest response body that's read.  nothing the bot asks for comes close,
+// and the length comes from the server, so it isn't trusted with how much memory to use.
+const MAX_BODY: usize = 16 * 1024 * 1024;
+
+fn check_body_size(length: usize) -> Result<(), Error> {
+    if length > MAX_BODY {
+        return Err(Error::Malformed(format!("body is over the {} byte limit", MAX_BODY)));
+    }
+    Ok(())
+}
+
+fn read_exact(stream: &mut impl Read, length: usize) -> Result<Vec<u8>, Error> {
+    check_body_size(length)?;
+    let mut bytes = vec![0; length];
+    match stream.read_exact(&mut bytes) {
+        Ok(()) => Ok(bytes),
+        Err(what_happened) => Err(read_error(what_happened, "response body"))
+    }
+}
+
+fn read_headers(stream: &mut impl BufRead) -> Result<Vec<(String, String)>, Error> {
+    let mut headers = vec![];
+    loop {
+        let line = read_line(stream)?;
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(Error::Malformed(format!("header {:?} has no colon", line)));
+        };
+        headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+    }
+}
+
+fn read_chunked_body(stream: &mut impl BufRead) -> Result<Vec<u8>, Error> {
+    let mut body = vec![];
+    loop {
+        // each chunk is "<size in hex>[;extensions]\r\n<that many bytes>\r\n",
+        // ending with a chunk of size 0 and then optional trailers.
+        let size_line = read_line(stream)?;
+        let size = size_line.split(';').next().unwrap_or_default().trim();
+        let Ok(size) = usize::from_str_radix(size, 16) else {
+            return Err(Error::Malformed(format!("chunk size {:?} is not hex", size_line)));
+        };
+        if size == 0 {
+            read_headers(stream)?;
+            return Ok(body);
+        }
+        check_body_size(body.len().saturating_add(size))?;
+        body.extend(read_exact(stream, size)?);
+        if !read_line(stream)?.is_empty() {
+            return Err(Error::Malformed("chunk is longer than its size".to_string()));
+        }
+    }
+}
+
+// reads one response off the connection.
+// also returns whether the connection can be used again afterwards.
+fn read_response(stream: &mut impl BufRead, method: &str) -> Result<(Response, bool), Error> {
+    let (version, status) = loop {
+        // the status line looks like "HTTP/1.1 200 OK".
+        let status_line = read_line(stream)?;
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or_default().to_string();
+        if !version.starts_with("HTTP/1.") {
+            return Err(Error::Malformed(format!("status line {:?} isn't http/1", status_line)));
+        }
+        let Some(Ok(status)) = parts.next().map(str::parse::<u16>) else {
+            return Err(Error::Malformed(format!("status line {:?} has no status", status_line)));
+        };
+        if (100..200).contains(&status) {
+            // informational responses like 100 continue come before the real one.
+            read_headers(stream)?;
+            continue;
+        }
+        break (version, status);
+    };
+
+    let headers = read_headers(stream)?;
+    let mut response = Response {
+        status,
+        headers,
+        body: vec![]
+    };
+
+    let wants_close = response.header("connection").is_some_and(|connection| connection.eq_ignore_ascii_case("close"))
+        || (version == "HTTP/1.0" && !response.header("connection").is_some_and(|connection| connection.eq_ignore_ascii_case("keep-alive")));
+    let is_chunked = response.header("transfer-encoding").is_some_and(|encoding| encoding.to_ascii_lowercase().ends_with("chunked"));
+    let content_length = response.header("content-length").map(str::parse::<usize>);
+
+    let reusable = if method == "HEAD" || status == 204 || status == 304 {
+        !wants_close
+    } else if is_chunked {
+        response.body = read_chunked_body(stream)?;
+        !wants_close
+    } else if let Some(content_length) = content_length {
+        let Ok(content_length) = content_length else {
+            return Err(Error::Malformed("content-length is not a number".to_string()));
+        };
+        response.body = read_exact(stream, content_length)?;
+        !wants_close
+    } else {
+        // no length given, so the body lasts until the server hangs up.
+        // one byte more than the limit is enough to tell it's too big.
+        if let Err(what_happened) = stream.take(MAX_BODY as u64 + 1).read_to_end(&mut response.body) {
+            return Err(read_error(what_happened, "response body"));
+        }
+        check_body_size(response.body.len())?;
+        false
+    };
+    Ok((response, reusable))
+}
+
+fn write_request(stream: &mut impl Write, domain: &str, request: &Request) -> Result<(), Error> {
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n", request.method, request.path, domain, request.body.len());
+    for (name, value) in request.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+
+    let written = stream.write_all(head.as_bytes())
+        .and_then(|()| stream.write_all(request.body))
+        .and_then(|()| stream.flush());
+    match written {
+        Ok(()) => Ok(()),
+        Err(what_happened) if is_timeout(&what_happened) => Err(Error::WriteTimeout("request".to_string())),
+        Err(what_happened) => Err(Error::Connection(format!("failed to write request: {}", what_happened)))
+    }
+}
+
+fn send_on(connection: &mut Connection, request: &Request) -> Result<(Response, bool), Error> {
+    write_request(connection.stream.get_mut(), &connection.server.domain, request)?;
+    read_response(&mut connection.stream, request.method)
+}
+
+// whether sending the request twice does the same as sending it once.
+// that's true of requests with an idempotency key too, since the server refuses the repeat.
+fn is_idempotent(request: &Request) -> bool {
+    matches!(request.method, "GET" | "HEAD" | "PUT" | "DELETE" | "OPTIONS")
+        || request.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("idempotency-key"))
+}
+
+// sends a request to the server, reusing an idle connection if there is one.
+// anything other than a 2xx response comes back as an error.
+pub fn send(server: &Server, request: &Request) -> Result<Response, Error> {
+    let (mut connection, is_reused) = match take_idle_connection(server) {
+        Some(connection) => (connection, true),
+        None => (connect(server, &Timeouts::default())?, false)
+    };
+
+    let (response, reusable) = match send_on(&mut connection, request) {
+        Ok(result) => result,
+        // the server is allowed to close idle connections whenever it likes,
+        // so a reused connection failing probably just means it's time for a fresh one.
+        // if it broke after the request was sent, though, the server might have acted on it
+        // before hanging up, so it's only sent again if that can't do it twice.
+        Err(Error::Connection(_)) if is_reused => {
+            connection = connect(server, &Timeouts::default())?;
+            send_on(&mut connection, request)?
+        },
+        Err(Error::ConnectionLost(_)) if is_reused && is_idempotent(request) => {
+            connection = connect(server, &Timeouts::default())?;
+            send_on(&mut connection, request)?
+        },
+        Err(what_happened) => {
+            return Err(what_happened);
+        }
+    };
+    if reusable {
+        return_idle_connection(connection);
+    }
+
+    match response.status {
+        200..=299 => Ok(response),
+        300..=399 => Err(Error::Redirection(response)),
+        400..=499 => Err(Error::Client(response)),
+        500..=599 => Err(Error::Server(response)),
+        _ => Err(Error::Malformed(format!("unknown status {}", response.status)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    use super::*;
+
+    // short enough that the tests don't drag on.
+    const TIMEOUTS: Timeouts = Timeouts {
+        connect: Duration::from_secs(1),
+        read: Duration::from_millis(200),
+        write: Duration::from_millis(200)
+    };
+
+    #[test]
+    fn permanent_errors() {
+        let client_error = |status| Error::Client(Response {
+            status,
+            headers: vec![],
+            body: vec![]
+        });
+        assert!(client_error(403).is_permanent());
+        assert!(client_error(404).is_permanent());
+        assert!(Error::InvalidRequest("bad id".to_string()).is_permanent());
+        // a bad token or a malformed request is the bot's problem, not the alarm's.
+        assert!(!client_error(400).is_permanent());
+        assert!(!client_error(401).is_permanent());
+        assert!(!client_error(408).is_permanent());
+        assert!(!Error::ReadTimeout("api".to_string()).is_permanent());
+    }
+
+    // accepts connections (the kernel does that without it being asked)
+    // but never reads from or writes to any of them.
+    fn silent_server() -> (TcpListener, (String, u16)) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, ("127.0.0.1".to_string(), port))
+    }
+
+    #[test]
+    fn content_length() {
+        let mut raw: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Thing: a:b\r\n\r\nhelloHTTP/1.1";
+        let (response, reusable) = read_response(&mut raw, "GET").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("x-thing"), Some("a:b"));
+        assert_eq!(response.body, b"hello");
+        assert!(reusable);
+        assert_eq!(raw, b"HTTP/1.1");
+    }
+
+    #[test]
+    fn chunked() {
+        let mut raw: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\nB\r\n, world!!!!\r\n0\r\nTrailer: yes\r\n\r\n";
+        let (response, reusable) = read_response(&mut raw, "GET").unwrap();
+        assert_eq!(response.body, b"hello, world!!!!");
+        assert!(reusable);
+        assert!(raw.is_empty());
+    }
+
+    #[test]
+    fn until_close() {
+        let mut raw: &[u8] = b"HTTP/1.0 404 Not Found\r\n\r\nnope";
+        let (response, reusable) = read_response(&mut raw, "GET").unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"nope");
+        assert!(!reusable);
+    }
+
+    #[test]
+    fn continue_then_no_content() {
+        let mut raw: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n";
+        let (response, reusable) = read_response(&mut raw, "PUT").unwrap();
+        assert_eq!(response.status, 204);
+        assert!(response.body.is_empty());
+        assert!(!reusable);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut not_http: &[u8] = b"SSH-2.0-OpenSSH\r\n\r\n";
+        assert!(matches!(read_response(&mut not_http, "GET"), Err(Error::Malformed(_))));
+        let mut truncated: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort";
+        assert!(matches!(read_response(&mut truncated, "GET"), Err(Error::ConnectionLost(_))));
+    }
+
+    #[test]
+    fn too_large() {
+        let too_large = |raw: &[u8]| {
+            let mut raw = raw;
+            matches!(read_response(&mut raw, "GET"), Err(Error::Malformed(_)))
+        };
+        // refused before anything's read, let alone allocated.
+        assert!(too_large(b"HTTP/1.1 200 OK\r\nContent-Length: 18446744073709551615\r\n\r\n"));
+        assert!(too_large(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nFFFFFFFFFFFFFFFF\r\n"));
+        // chunks that are each small enough, but not together.
+        let chunk = format!("{:x}\r\n{}\r\n", MAX_BODY / 2 + 1, "a".repeat(MAX_BODY / 2 + 1));
+        assert!(too_large(format!("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{}{}0\r\n\r\n", chunk, chunk).as_bytes()));
+        assert!(too_large(format!("HTTP/1.0 200 OK\r\n\r\n{}", "a".repeat(MAX_BODY + 1)).as_bytes()));
+        let just_fits = format!("HTTP/1.0 200 OK\r\n\r\n{}", "a".repeat(MAX_BODY));
+        assert_eq!(read_response(&mut just_fits.as_bytes(), "GET").unwrap().0.body.len(), MAX_BODY);
+    }
+
+    // answers the first request on each connection, and hangs up on the second
+    // without answering, like a server closing an idle connection just as it's reused.
+    // gives back the server and how many requests it's read.
+    fn hang_up_server() -> (Server, Arc<Mutex<usize>>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                for request_on_connection in 0.. {
+                    let mut content_length = 0;
+                    loop {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                            break;
+                        }
+                        if let Some(length) = line.strip_prefix("Content-Length: ") {
+                            content_length = length.trim().parse().unwrap();
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    let _ = read_exact(&mut reader, content_length);
+                    *counter.lock().unwrap() += 1;
+                    if request_on_connection == 1 {
+                        break;
+                    }
+                    let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+                }
+            }
+        });
+        let server = Server {
+            domain: "127.0.0.1".to_string(),
+            address: ("127.0.0.1".to_string(), port),
+            tls: false
+        };
+        (server, requests)
+    }
+
+    #[test]
+    fn only_resends_what_can_be_done_twice() {
+        let (server, requests) = hang_up_server();
+        let request = |method| Request {
+            method,
+            path: "/",
+            headers: &[],
+            body: b""
+        };
+        assert!(send(&server, &request("GET")).is_ok());
+        // the post might have gone through before the server hung up, so it's not sent again.
+        assert!(matches!(send(&server, &request("POST")), Err(Error::ConnectionLost(_))));
+        assert_eq!(*requests.lock().unwrap(), 2);
+
+        assert!(send(&server, &request("GET")).is_ok());
+        assert!(send(&server, &request("PUT")).is_ok());
+        assert_eq!(*requests.lock().unwrap(), 5);
+        let with_key = Request {
+            method: "POST",
+            path: "/",
+            headers: &[("Idempotency-Key", "once")],
+            body: b"{}"
+        };
+        assert!(send(&server, &with_key).is_ok());
+        assert_eq!(*requests.lock().unwrap(), 7);
+    }
+
+    #[test]
+    fn tls_handshake_timeout() {
+        let (_listener, (host, port)) = silent_server();
+        let server = Server {
+            domain: "localhost".to_string(),
+            address: (host, port),
+            tls: true
+        };
+        assert!(matches!(connect(&server, &TIMEOUTS), Err(Error::ConnectTimeout(_))));
+    }
+
+    #[test]
+    fn read_timeout() {
+        let (_listener, (host, port)) = silent_server();
+        let mut stream = connect_tcp((&host, port), &TIMEOUTS).unwrap();
+        let request = Request {
+            method: "GET",
+            path: "/",
+            headers: &[],
+            body: &[]
+        };
+        write_request(&mut stream, "localhost", &request).unwrap();
+        let mut stream = BufReader::new(stream);
+        assert!(matches!(read_response(&mut stream, "GET"), Err(Error::ReadTimeout(_))));
+    }
+
+    #[test]
+    fn write_timeout() {
+        let (_listener, (host, port)) = silent_server();
+        let mut stream = connect_tcp((&host, port), &TIMEOUTS).unwrap();
+        // far more than the socket buffers on both ends can hold,
+        // so the write has to wait on the server reading some.
+        let body = vec![0; 64 * 1024 * 1024];
+        let request = Request {
+            method: "POST",
+            path: "/",
+            headers: &[],
+            body: &body
+        };
+        assert!(matches!(write_request(&mut stream, "localhost", &request), Err(Error::WriteTimeout(_))));
+    }
+}
@@ -0,0 +1,495 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::Utc;
+
+use crate::alarm::Alarm;
+use crate::alarm_heap::AlarmHeap;
+use crate::config;
+use crate::json;
+use crate::json::Value;
+use crate::store::LoadSummary;
+use crate::ulid;
+
+// the journal is the alternative to the one-file-per-alarm layout in file.rs.
+// instead of lots of little files, every change to the alarms is appended
+// to a single log, one record per line:
+// <crc32 of the rest of the line, as 8 hex digits>\t<operation>\t<fields...>
+//
+// the operations are:
+// create\t<alarm as json, from Alarm::to_json>
+// update\t<alarm as json, from Alarm::to_json>
+// cancel\t<channel id>\t<message id>
+// fire\t<channel id>\t<message id>
+//
+// json escapes tabs and newlines inside strings,
+// so a record always stays on one line.
+//
+// a record whose ids aren't ulids is treated like a corrupt one,
+// since the ids end up in paths when alarms are exported or dead lettered.
+//
+// every JOURNAL_COMPACT_AFTER records, the live alarms get written out
+// to a snapshot (in the same format, all creates), and the journal is emptied.
+// loading reads the snapshot and then replays the journal on top of it.
+
+const JOURNAL_FILE: &str = "journal";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TEMP_FILE: &str = "snapshot.tmp";
+
+// counts the records appended since the last compaction.
+// it's also what keeps the two threads from writing to the journal at once.
+static RECORDS_SINCE_COMPACTION: Mutex<usize> = Mutex::new(0);
+
+// live alarms, keyed by (channel id, message id).
+type Alarms = HashMap<(String, String), Alarm>;
+
+enum Record {
+    Create(Alarm),
+    Update(Alarm),
+    Cancel(String, String),
+    Fire(String, String)
+}
+
+const fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    let mut index = 0;
+    while index < bytes.len() {
+        crc ^= bytes[index] as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        index += 1;
+    }
+    !crc
+}
+
+fn encode(record: &Record) -> String {
+    let body = match record {
+        Record::Create(alarm) | Record::Update(alarm) => {
+            let operation = if let Record::Create(_) = record { "create" } else { "update" };
+            format!("{}\t{}", operation, json::stringify(&alarm.to_json()))
+        },
+        Record::Cancel(channel_id, message_id) => format!("cancel\t{}\t{}", channel_id, message_id),
+        Record::Fire(channel_id, message_id) => format!("fire\t{}\t{}", channel_id, message_id)
+    };
+    format!("{:08x}\t{}\n", crc32(body.as_bytes()), body)
+}
+
+fn check_ids(channel_id: &str, message_id: &str) -> Result<(), String> {
+    if !ulid::is_valid(channel_id) || !ulid::is_valid(message_id) {
+        return Err("record has an invalid id".to_string());
+    }
+    Ok(())
+}
+
+fn decode(line: &str) -> Result<Record, String> {
+    let Some((checksum, body)) = line.split_once("\t") else {
+        return Err("record has no checksum".to_string());
+    };
+    let Ok(checksum) = u32::from_str_radix(checksum, 16) else {
+        return Err("record checksum is not hex".to_string());
+    };
+    if checksum != crc32(body.as_bytes()) {
+        return Err("record checksum does not match".to_string());
+    }
+
+    let fields: Vec<&str> = body.split("\t").collect();
+    match fields.as_slice() {
+        [operation @ ("create"|"update"), saved] => {
+            let Ok((Value::Object(saved), _)) = json::parse_value(saved.as_bytes(), 0) else {
+                return Err("record has an invalid alarm".to_string());
+            };
+            let alarm = Alarm::from_json(&saved)?;
+            check_ids(&alarm.channel_id, &alarm.message_id)?;
+            if *operation == "create" {
+                Ok(Record::Create(alarm))
+            } else {
+                Ok(Record::Update(alarm))
+            }
+        },
+        ["cancel", channel_id, message_id] => {
+            check_ids(channel_id, message_id)?;
+            Ok(Record::Cancel(channel_id.to_string(), message_id.to_string()))
+        },
+        ["fire", channel_id, message_id] => {
+            check_ids(channel_id, message_id)?;
+            Ok(Record::Fire(channel_id.to_string(), message_id.to_string()))
+        },
+        _ => Err("record is malformed".to_string())
+    }
+}
+
+// applies every intact record in the file to the alarms.
+// records that are torn or fail their checksum are reported and added to corrupt,
+// one per line, so they can be quarantined.  if corrupt is None, they're skipped quietly.
+fn replay(path: &Path, alarms: &mut Alarms, mut corrupt: Option<&mut Vec<u8>>) -> Result<(), String> {
+    if std::fs::exists(path).ok().is_none_or(|exists| !exists) {
+        return Ok(());
+    }
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(what_happened) => {
+            return Err(format!("failed to read {}: {}", path.display(), what_happened));
+        }
+    };
+
+    for (line_number, line) in contents.split_inclusive(|byte| *byte == b'\n').enumerate() {
+        let reason = match line.strip_suffix(b"\n").map(std::str::from_utf8) {
+            None => "torn record".to_string(),
+            Some(Err(_)) => "record is not valid utf8".to_string(),
+            Some(Ok(line)) => match decode(line) {
+                Ok(Record::Create(alarm) | Record::Update(alarm)) => {
+                    alarms.insert((alarm.channel_id.clone(), alarm.message_id.clone()), alarm);
+                    continue;
+                },
+                Ok(Record::Cancel(channel_id, message_id) | Record::Fire(channel_id, message_id)) => {
+                    alarms.remove(&(channel_id, message_id));
+                    continue;
+                },
+                Err(what_happened) => what_happened
+            }
+        };
+        if let Some(corrupt) = corrupt.as_deref_mut() {
+            println!("journal: {} line {}: {}", path.display(), line_number + 1, reason);
+            corrupt.extend_from_slice(line);
+            if !line.ends_with(b"\n") {
+                corrupt.push(b'\n');
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_alarms(top_folder: &Path, mut corrupt: Option<&mut Vec<u8>>) -> Result<Alarms, String> {
+    let mut alarms = HashMap::new();
+    replay(&top_folder.join(SNAPSHOT_FILE), &mut alarms, corrupt.as_deref_mut())?;
+    replay(&top_folder.join(JOURNAL_FILE), &mut alarms, corrupt)?;
+    Ok(alarms)
+}
+
+fn create_top_folder() -> Result<&'static Path, String> {
+    let top_folder = Path::new(config::WHERE_TO_SAVE);
+    if std::fs::exists(top_folder).ok().is_none_or(|exists| !exists) {
+        let Ok(()) = std::fs::create_dir_all(top_folder) else {
+            return Err("failed to create top folder".to_string());
+        };
+    }
+    Ok(top_folder)
+}
+
+fn open_journal(top_folder: &Path) -> Result<File, String> {
+    let Ok(mut file) = OpenOptions::new().create(true).read(true).append(true).open(top_folder.join(JOURNAL_FILE)) else {
+        return Err("failed to open journal".to_string());
+    };
+    // if the last record was torn, start on a new line
+    // rather than gluing the next record onto it.
+    let mut last_byte = [b'\n'];
+    if file.seek(SeekFrom::End(-1)).is_ok() && file.read_exact(&mut last_byte).is_ok() && last_byte[0] != b'\n' {
+        let Ok(()) = file.write_all(b"\n") else {
+            return Err("failed to end torn journal record".to_string());
+        };
+    }
+    Ok(file)
+}
+
+fn write_snapshot(path: &Path, alarms: &Alarms) -> Result<(), String> {
+    let Ok(mut file) = File::create(path) else {
+        return Err("failed to create snapshot".to_string());
+    };
+    for alarm in alarms.values() {
+        let Ok(()) = file.write_all(encode(&Record::Create(alarm.clone())).as_bytes()) else {
+            return Err("failed to write snapshot".to_string());
+        };
+    }
+    let Ok(()) = file.sync_all() else {
+        return Err("failed to sync snapshot".to_string());
+    };
+    Ok(())
+}
+
+// must be called with RECORDS_SINCE_COMPACTION locked.
+fn compact(top_folder: &Path) -> Result<(), String> {
+    // anything corrupt was already reported when the journal was loaded.
+    let alarms = read_alarms(top_folder, None)?;
+
+    // the snapshot is written to the side and renamed into place,
+    // so a crash partway through never leaves a half-written snapshot.
+    // if it crashes after the rename but before the journal is emptied,
+    // replaying the old journal on top of the new snapshot is harmless.
+    let temp_path = top_folder.join(SNAPSHOT_TEMP_FILE);
+    write_snapshot(&temp_path, &alarms)?;
+    let Ok(()) = std::fs::rename(&temp_path, top_folder.join(SNAPSHOT_FILE)) else {
+        return Err("failed to move snapshot into place".to_string());
+    };
+    let Ok(journal) = File::create(top_folder.join(JOURNAL_FILE)) else {
+        return Err("failed to empty journal after compaction".to_string());
+    };
+    let Ok(()) = journal.sync_all() else {
+        return Err("failed to sync journal after compaction".to_string());
+    };
+    Ok(())
+}
+
+fn append(record: &Record) -> Result<(), String> {
+    let Ok(mut records_since_compaction) = RECORDS_SINCE_COMPACTION.lock() else {
+        return Err("journal mutex has been poisoned".to_string());
+    };
+
+    let top_folder = create_top_folder()?;
+    let mut journal = open_journal(top_folder)?;
+    let Ok(()) = journal.write_all(encode(record).as_bytes()) else {
+        return Err("failed to append to journal".to_string());
+    };
+    let Ok(()) = journal.sync_data() else {
+        return Err("failed to sync journal".to_string());
+    };
+    std::mem::drop(journal);
+
+    *records_since_compaction += 1;
+    if *records_since_compaction >= config::JOURNAL_COMPACT_AFTER {
+        compact(top_folder)?;
+        *records_since_compaction = 0;
+    }
+    Ok(())
+}
+
+// keeps corrupt records out of the way of compaction,
+// so they can still be looked at later.
+fn quarantine(corrupt: &[u8]) -> Result<(), String> {
+    let quarantine_dir = Path::new(config::QUARANTINE_DIR);
+    if let Err(what_happened) = std::fs::create_dir_all(quarantine_dir) {
+        return Err(format!("failed to create quarantine folder {}: {}", quarantine_dir.display(), what_happened));
+    }
+    let destination = quarantine_dir.join(format!("journal.{}", Utc::now().timestamp_millis()));
+    if let Err(what_happened) = std::fs::write(&destination, corrupt) {
+        return Err(format!("failed to quarantine corrupt journal records to {}: {}", destination.display(), what_happened));
+    }
+    println!("journal: quarantined corrupt records to {}", destination.display());
+    Ok(())
+}
+
+pub fn load() -> Result<(AlarmHeap, LoadSummary), String> {
+    let Ok(mut records_since_compaction) = RECORDS_SINCE_COMPACTION.lock() else {
+        return Err("journal mutex has been poisoned".to_string());
+    };
+
+    let top_folder = create_top_folder()?;
+    let mut corrupt = vec![];
+    let alarms = read_alarms(top_folder, Some(&mut corrupt))?;
+    let mut summary = LoadSummary::default();
+    if !corrupt.is_empty() {
+        quarantine(&corrupt)?;
+        summary.quarantined = corrupt.iter().filter(|byte| **byte == b'\n').count();
+    }
+
+    // start fresh from a snapshot.  this drops any corrupt records
+    // (including a torn one at the end, which the next append would get glued onto),
+    // and keeps the journal from growing forever
+    // across restarts that happen before it reaches JOURNAL_COMPACT_AFTER.
+    compact(top_folder)?;
+    *records_since_compaction = 0;
+
+    let mut alarm_heap = AlarmHeap::default();
+    for alarm in alarms.into_values() {
+        alarm_heap.push(alarm);
+        summary.loaded += 1;
+    }
+    Ok((alarm_heap, summary))
+}
+
+// the alarms as they stand, without quarantining or compacting anything.
+pub fn read() -> Result<(AlarmHeap, LoadSummary), String> {
+    let Ok(_records_since_compaction) = RECORDS_SINCE_COMPACTION.lock() else {
+        return Err("journal mutex has been poisoned".to_string());
+    };
+
+    let mut corrupt = vec![];
+    let alarms = read_alarms(Path::new(config::WHERE_TO_SAVE), Some(&mut corrupt))?;
+    let mut summary = LoadSummary {
+        skipped: corrupt.iter().filter(|byte| **byte == b'\n').count(),
+        ..LoadSummary::default()
+    };
+    let mut alarm_heap = AlarmHeap::default();
+    for alarm in alarms.into_values() {
+        alarm_heap.push(alarm);
+        summary.loaded += 1;
+    }
+    Ok((alarm_heap, summary))
+}
+
+pub fn record_create(alarm: &Alarm) -> Result<(), String> {
+    append(&Record::Create(alarm.clone()))
+}
+
+pub fn record_update(alarm: &Alarm) -> Result<(), String> {
+    append(&Record::Update(alarm.clone()))
+}
+
+pub fn record_cancel(alarm: &Alarm) -> Result<(), String> {
+    append(&Record::Cancel(alarm.channel_id.clone(), alarm.message_id.clone()))
+}
+
+pub fn record_fire(alarm: &Alarm) -> Result<(), String> {
+    append(&Record::Fire(alarm.channel_id.clone(), alarm.message_id.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use chrono::DateTime;
+
+    const CHANNEL_ID: &str = "01ARZ3NDEKTSV4RRFFQ69G5FAV";
+
+    fn alarm(message_id: &str, what: &str) -> Alarm {
+        Alarm {
+            when: DateTime::from_timestamp(1_800_000_000, 0).unwrap().naive_utc(),
+            what: what.to_string(),
+            channel_id: CHANNEL_ID.to_string(),
+            message_id: message_id.to_string(),
+            author: None,
+            created: None,
+            recurrence: None,
+            target: None,
+            attempts: 0,
+            reacted: false,
+            retry_at: None
+        }
+    }
+
+    // an empty folder to keep a journal in.
+    fn temp_folder(test_name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("thats-quite-alarming-journal-{}-{}", test_name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    fn write_records(path: &Path, records: &[Record]) {
+        let contents: String = records.iter().map(encode).collect();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    // message id to text, for each live alarm.
+    fn texts(alarms: &Alarms) -> HashMap<&str, &str> {
+        alarms.values().map(|alarm| (alarm.message_id.as_str(), alarm.what.as_str())).collect()
+    }
+
+    #[test]
+    fn replays_every_operation() {
+        let folder = temp_folder("replay");
+        write_records(&folder.join(JOURNAL_FILE), &[
+            Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR0", "first")),
+            Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR1", "cancelled")),
+            Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR2", "fired")),
+            Record::Update(alarm("01BX5ZZKBKACTAV9WEVGEMMVR0", "updated")),
+            Record::Cancel(CHANNEL_ID.to_string(), "01BX5ZZKBKACTAV9WEVGEMMVR1".to_string()),
+            Record::Fire(CHANNEL_ID.to_string(), "01BX5ZZKBKACTAV9WEVGEMMVR2".to_string())
+        ]);
+
+        let mut corrupt = vec![];
+        let alarms = read_alarms(&folder, Some(&mut corrupt)).unwrap();
+        assert_eq!(texts(&alarms), HashMap::from([("01BX5ZZKBKACTAV9WEVGEMMVR0", "updated")]));
+        assert!(corrupt.is_empty());
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn skips_records_that_fail_their_checksum() {
+        let folder = temp_folder("checksum");
+        let good = encode(&Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR0", "good")));
+        let bad = encode(&Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR1", "tampered"))).replace("tampered", "tampering");
+        std::fs::write(folder.join(JOURNAL_FILE), format!("{}{}", bad, good)).unwrap();
+
+        let mut corrupt = vec![];
+        let alarms = read_alarms(&folder, Some(&mut corrupt)).unwrap();
+        assert_eq!(texts(&alarms), HashMap::from([("01BX5ZZKBKACTAV9WEVGEMMVR0", "good")]));
+        assert_eq!(String::from_utf8(corrupt).unwrap(), bad);
+        assert_eq!(decode(bad.trim_end()).err().as_deref(), Some("record checksum does not match"));
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn skips_records_with_invalid_ids() {
+        let folder = temp_folder("invalid_ids");
+        let good = encode(&Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR0", "good")));
+        let bad_create = encode(&Record::Create(alarm("../../etc/passwd", "escaped")));
+        let bad_fire = encode(&Record::Fire("..".to_string(), "01BX5ZZKBKACTAV9WEVGEMMVR0".to_string()));
+        std::fs::write(folder.join(JOURNAL_FILE), format!("{}{}{}", good, bad_create, bad_fire)).unwrap();
+
+        let mut corrupt = vec![];
+        let alarms = read_alarms(&folder, Some(&mut corrupt)).unwrap();
+        assert_eq!(texts(&alarms), HashMap::from([("01BX5ZZKBKACTAV9WEVGEMMVR0", "good")]));
+        assert_eq!(String::from_utf8(corrupt).unwrap(), format!("{}{}", bad_create, bad_fire));
+        assert_eq!(decode(bad_create.trim_end()).err().as_deref(), Some("record has an invalid id"));
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn skips_a_torn_last_record() {
+        let folder = temp_folder("torn");
+        let journal_path = folder.join(JOURNAL_FILE);
+        let good = encode(&Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR0", "good")));
+        let whole = encode(&Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR1", "torn")));
+        let torn = &whole[..whole.len() / 2];
+        std::fs::write(&journal_path, format!("{}{}", good, torn)).unwrap();
+
+        let mut corrupt = vec![];
+        let alarms = read_alarms(&folder, Some(&mut corrupt)).unwrap();
+        assert_eq!(texts(&alarms), HashMap::from([("01BX5ZZKBKACTAV9WEVGEMMVR0", "good")]));
+        assert_eq!(String::from_utf8(corrupt).unwrap(), format!("{}\n", torn));
+
+        // the next record goes on its own line instead of being glued onto the torn one.
+        let mut journal = open_journal(&folder).unwrap();
+        journal.write_all(encode(&Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR2", "after"))).as_bytes()).unwrap();
+        std::mem::drop(journal);
+        let alarms = read_alarms(&folder, None).unwrap();
+        assert_eq!(texts(&alarms), HashMap::from([
+            ("01BX5ZZKBKACTAV9WEVGEMMVR0", "good"),
+            ("01BX5ZZKBKACTAV9WEVGEMMVR2", "after")
+        ]));
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn compacts_into_a_snapshot() {
+        let folder = temp_folder("compact");
+        write_records(&folder.join(SNAPSHOT_FILE), &[
+            Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR0", "from the snapshot")),
+            Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR1", "cancelled later"))
+        ]);
+        write_records(&folder.join(JOURNAL_FILE), &[
+            Record::Cancel(CHANNEL_ID.to_string(), "01BX5ZZKBKACTAV9WEVGEMMVR1".to_string()),
+            Record::Create(alarm("01BX5ZZKBKACTAV9WEVGEMMVR2", "from the journal"))
+        ]);
+        let expected = HashMap::from([
+            ("01BX5ZZKBKACTAV9WEVGEMMVR0", "from the snapshot"),
+            ("01BX5ZZKBKACTAV9WEVGEMMVR2", "from the journal")
+        ]);
+        assert_eq!(texts(&read_alarms(&folder, None).unwrap()), expected);
+
+        compact(&folder).unwrap();
+        assert_eq!(std::fs::read(folder.join(JOURNAL_FILE)).unwrap(), b"");
+        assert!(!folder.join(SNAPSHOT_TEMP_FILE).exists());
+        let snapshot = std::fs::read_to_string(folder.join(SNAPSHOT_FILE)).unwrap();
+        assert_eq!(snapshot.lines().count(), 2);
+        assert!(snapshot.lines().all(|line| matches!(decode(line), Ok(Record::Create(_)))));
+
+        assert_eq!(texts(&read_alarms(&folder, None).unwrap()), expected);
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 // based on the charts on json.org
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum IntOrFloat {
     Int(i64),
     Float(f64)
@@ -24,7 +24,7 @@ impl IntOrFloat {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     Null,
     Boolean(bool),
@@ -222,10 +222,7 @@ fn parse_number(string: &[u8], start_index: usize) -> ParseResult {
             number
         };
 
-        let fraction = match right_of_decimal {
-            Some(number) => number,
-            None => 0f64
-        };
+        let fraction = right_of_decimal.unwrap_or(0f64);
         let mantissa = signf * (
             (left_of_decimal as f64) + fraction
         );
@@ -547,6 +544,102 @@ pub fn parse_value(string: &[u8], start_index: usize) -> Result<(Value, usize),
     Ok((value, end_index))
 }
 
+fn stringify_string(string: &str, output: &mut String) {
+    output.push('"');
+    for ch in string.chars() {
+        match ch {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            '\u{8}' => output.push_str("\\b"), // backspace
+            '\u{c}' => output.push_str("\\f"), // formfeed
+            '\u{0}'..='\u{1f}' => output.push_str(&format!("\\u{:04x}", ch as u32)),
+            _ => output.push(ch)
+        }
+    }
+    output.push('"');
+}
+
+// indent is None for compact output,
+// or how deep the value is nested for pretty output.
+fn stringify_into(value: &Value, output: &mut String, indent: Option<usize>) {
+    const INDENT: &str = "    ";
+    fn newline(output: &mut String, depth: Option<usize>) {
+        if let Some(depth) = depth {
+            output.push('\n');
+            output.push_str(&INDENT.repeat(depth));
+        }
+    }
+    let inner_indent = indent.map(|depth| depth + 1);
+
+    match value {
+        Value::Null => output.push_str("null"),
+        Value::Boolean(true) => output.push_str("true"),
+        Value::Boolean(false) => output.push_str("false"),
+        Value::Number(IntOrFloat::Int(i)) => output.push_str(&i.to_string()),
+        Value::Number(IntOrFloat::Float(f)) => {
+            // json has no way to write nan or infinity.
+            if f.is_finite() {
+                // debug formatting always keeps the decimal point,
+                // so the number reads back as a float.
+                output.push_str(&format!("{:?}", f));
+            } else {
+                output.push_str("null");
+            }
+        },
+        Value::String(string) => stringify_string(string, output),
+        Value::Array(array) => {
+            output.push('[');
+            for (index, element) in array.iter().enumerate() {
+                if index != 0 {
+                    output.push(',');
+                }
+                newline(output, inner_indent);
+                stringify_into(element, output, inner_indent);
+            }
+            if !array.is_empty() {
+                newline(output, indent);
+            }
+            output.push(']');
+        },
+        Value::Object(object) => {
+            output.push('{');
+            for (index, (key, element)) in object.iter().enumerate() {
+                if index != 0 {
+                    output.push(',');
+                }
+                newline(output, inner_indent);
+                stringify_string(key, output);
+                output.push(':');
+                if indent.is_some() {
+                    output.push(' ');
+                }
+                stringify_into(element, output, inner_indent);
+            }
+            if !object.is_empty() {
+                newline(output, indent);
+            }
+            output.push('}');
+        }
+    }
+}
+
+// the whole value on one line, with no extra whitespace.
+pub fn stringify(value: &Value) -> String {
+    let mut output = String::new();
+    stringify_into(value, &mut output, None);
+    output
+}
+
+// for people to read: one array element or object member per line, indented 4 spaces per level.
+pub fn stringify_pretty(value: &Value) -> String {
+    let mut output = String::new();
+    stringify_into(value, &mut output, Some(0));
+    output
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -711,4 +804,69 @@ mod tests {
             ]))
         , 79)));
     }
+
+    fn round_trip(value: Value) {
+        let compact = stringify(&value);
+        assert_eq!(parse_value(compact.as_bytes(), 0), Ok((value, compact.len())));
+        let (value, _) = parse_value(compact.as_bytes(), 0).unwrap();
+        let pretty = stringify_pretty(&value);
+        assert_eq!(parse_value(pretty.as_bytes(), 0), Ok((value, pretty.len())));
+    }
+
+    #[test]
+    fn stringify_escapes() {
+        assert_eq!(stringify(&Value::String("\"\\/".into())), r#""\"\\/""#);
+        assert_eq!(stringify(&Value::String("\n\r\t".into())), r#""\n\r\t""#);
+        assert_eq!(stringify(&Value::String([8 as char, 12 as char].iter().collect())), r#""\b\f""#);
+        assert_eq!(stringify(&Value::String("\u{0}\u{1f}\u{7f}".into())), "\"\\u0000\\u001f\u{7f}\"");
+        assert_eq!(stringify(&Value::String("👌".into())), "\"👌\"");
+    }
+
+    #[test]
+    fn stringify_numbers() {
+        assert_eq!(stringify(&Value::Number(IntOrFloat::Int(-67))), "-67");
+        assert_eq!(stringify(&Value::Number(IntOrFloat::Float(7f64))), "7.0");
+        assert_eq!(stringify(&Value::Number(IntOrFloat::Float(f64::NAN))), "null");
+        assert_eq!(stringify(&Value::Number(IntOrFloat::Float(f64::INFINITY))), "null");
+    }
+
+    #[test]
+    fn stringify_pretty_layout() {
+        assert_eq!(stringify_pretty(&Value::Array(vec![])), "[]");
+        assert_eq!(stringify_pretty(&Value::Object(HashMap::new())), "{}");
+        assert_eq!(
+            stringify_pretty(&Value::Array(vec![
+                Value::Number(IntOrFloat::Int(1)),
+                Value::Object(HashMap::from([("two".into(), Value::Array(vec![Value::Null]))]))
+            ])),
+            "[\n    1,\n    {\n        \"two\": [\n            null\n        ]\n    }\n]"
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(Value::Null);
+        round_trip(Value::Boolean(true));
+        round_trip(Value::Boolean(false));
+        round_trip(Value::Number(IntOrFloat::Int(0)));
+        round_trip(Value::Number(IntOrFloat::Int(i64::MAX)));
+        round_trip(Value::Number(IntOrFloat::Int(-67)));
+        round_trip(Value::Number(IntOrFloat::Float(3.5)));
+        round_trip(Value::Number(IntOrFloat::Float(-23.45)));
+        round_trip(Value::String("".into()));
+        round_trip(Value::String("wish mom a\nhappy \"birthday\"\t\\o/ 🎂".into()));
+        round_trip(Value::String((0u8..0x20).map(char::from).collect()));
+        round_trip(Value::Array(vec![]));
+        round_trip(Value::Object(HashMap::new()));
+        round_trip(Value::Object(HashMap::from([
+            ("content".into(), Value::String("line one\nline two".into())),
+            ("embeds".into(), Value::Array(vec![])),
+            ("replies".into(), Value::Array(vec![
+                Value::Object(HashMap::from([
+                    ("id".into(), Value::String("01BX5ZZKBKACTAV9WEVGEMMVRZ".into())),
+                    ("mention".into(), Value::Boolean(true))
+                ]))
+            ]))
+        ])));
+    }
 }
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::config;
+use crate::json::Value;
+use crate::saved_map::SavedMap;
+use crate::ulid;
+
+// remembers the newest message the bot has seen in each channel,
+// so after being offline it can go back and catch up on what it missed.
+// it's kept in LAST_SEEN_FILE as one json object:
+// {"<channel id>":"<message id>", ...}
+// message ids are ulids, which sort in the order they were sent,
+// so newer just means bigger.
+
+fn message_id(value: &Value) -> Option<String> {
+    match value {
+        Value::String(message_id) if ulid::is_valid(message_id) => Some(message_id.to_string()),
+        _ => None
+    }
+}
+
+fn message_id_to_json(message_id: &String) -> Value {
+    Value::String(message_id.to_string())
+}
+
+struct LastSeen {
+    channels: SavedMap<String>,
+    // when it first changed after it was last written, or None if the file's up to date.
+    changed_since: Option<Instant>
+}
+
+static LAST_SEEN: Mutex<LastSeen> = Mutex::new(LastSeen {
+    channels: SavedMap::new(config::LAST_SEEN_FILE, message_id, message_id_to_json),
+    changed_since: None
+});
+
+// changes are kept in memory and written at most this often,
+// so a busy server doesn't mean a write for every message.
+// messages the bot acts on are flushed before it acts, so after a crash,
+// only messages it ignored anyway can be caught up on again.
+const SAVE_EVERY: Duration = Duration::from_secs(30);
+
+// every channel the bot has seen a message in, and the newest message it saw there.
+pub fn all() -> Result<HashMap<String, String>, String> {
+    let Ok(mut last_seen) = LAST_SEEN.lock() else {
+        return Err("last seen mutex has been poisoned".to_string());
+    };
+    Ok(last_seen.channels.entries().clone())
+}
+
+// marks the message as seen.
+// gives back false if it (or a newer message in the channel) has been seen already,
+// so the same message can't be handled twice, like when it's caught up on and then comes in live too.
+// ids that aren't ulids can't be compared, so those messages are always new.
+pub fn claim(channel_id: &str, message_id: &str) -> Result<bool, String> {
+    if !ulid::is_valid(channel_id) || !ulid::is_valid(message_id) {
+        return Ok(true);
+    }
+    let Ok(mut last_seen) = LAST_SEEN.lock() else {
+        return Err("last seen mutex has been poisoned".to_string());
+    };
+    if last_seen.channels.entries().get(channel_id).is_some_and(|newest| newest.as_str() >= message_id) {
+        return Ok(false);
+    }
+    last_seen.channels.entries().insert(channel_id.to_string(), message_id.to_string());
+    last_seen.changed_since.get_or_insert_with(Instant::now);
+    Ok(true)
+}
+
+fn save(last_seen: &mut LastSeen) -> Result<(), String> {
+    if last_seen.changed_since.is_none() {
+        return Ok(());
+    }
+    last_seen.channels.save()?;
+    last_seen.changed_since = None;
+    Ok(())
+}
+
+// writes any changes, if they've waited long enough.
+pub fn save_if_due() -> Result<(), String> {
+    let Ok(mut last_seen) = LAST_SEEN.lock() else {
+        return Err("last seen mutex has been poisoned".to_string());
+    };
+    if last_seen.changed_since.is_some_and(|changed_since| changed_since.elapsed() >= SAVE_EVERY) {
+        save(&mut last_seen)?;
+    }
+    Ok(())
+}
+
+// writes any changes right away, for when the bot's about to act on a message, or stopping.
+pub fn flush() -> Result<(), String> {
+    let Ok(mut last_seen) = LAST_SEEN.lock() else {
+        return Err("last seen mutex has been poisoned".to_string());
+    };
+    save(&mut last_seen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claims_each_message_once() {
+        const CHANNEL_ID: &str = "01J0000000000000000000C1A1";
+        assert_eq!(claim(CHANNEL_ID, "01J00000000000000000000002"), Ok(true));
+        assert_eq!(claim(CHANNEL_ID, "01J00000000000000000000002"), Ok(false));
+        // older than what's been seen, so it must have been seen too.
+        assert_eq!(claim(CHANNEL_ID, "01J00000000000000000000001"), Ok(false));
+        assert_eq!(claim(CHANNEL_ID, "01J00000000000000000000003"), Ok(true));
+        // each channel's counted separately.
+        assert_eq!(claim("01J0000000000000000000C1A2", "01J00000000000000000000001"), Ok(true));
+        // and ids that aren't ulids can't be compared at all.
+        assert_eq!(claim(CHANNEL_ID, "not an id"), Ok(true));
+        assert_eq!(claim(CHANNEL_ID, "not an id"), Ok(true));
+        assert_eq!(all().unwrap().get(CHANNEL_ID).map(String::as_str), Some("01J00000000000000000000003"));
+    }
+}
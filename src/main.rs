@@ -1,28 +1,207 @@
 pub mod alarm;
 pub mod alarm_heap;
+pub mod backup;
+pub mod cache;
+pub mod commands;
 pub mod config;
 pub mod event_listener;
 pub mod file;
+pub mod history;
+pub mod http;
+pub mod journal;
 pub mod json;
+pub mod last_seen;
+pub mod outbox;
+pub mod saved_map;
+pub mod settings;
 pub mod stoat_api;
+pub mod store;
+pub mod timezones;
+pub mod ulid;
+pub mod url;
 
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::thread::JoinHandle;
 use std::time::Duration;
+use std::time::Instant;
 
 use chrono::Utc;
 
+use crate::alarm_heap::AlarmHeap;
+
+const USAGE: &str = "usage:
+    thats-quite-alarming
+        run the bot.
+    thats-quite-alarming export <file>
+        write every saved alarm to file as json.
+    thats-quite-alarming import <file> [--merge|--replace]
+        load alarms from a file made by export.
+        --merge (the default) keeps the saved alarms and skips imported ones that are already saved.
+        --replace removes every saved alarm that isn't in the file.
+        if anything goes wrong partway, the alarms are put back how they were.
+    neither of these will run while the bot is running.";
+
+fn export(path: &str) -> Result<(), String> {
+    // this goes to a file rather than stdout,
+    // because reading the alarms can print warnings to stdout.
+    let document = backup::export()?;
+    if let Err(what_happened) = std::fs::write(path, document) {
+        return Err(format!("failed to write {}: {}", path, what_happened));
+    }
+    Ok(())
+}
+
+fn import(path: &str, mode: backup::ImportMode) -> Result<(), String> {
+    let document = match std::fs::read(path) {
+        Ok(document) => document,
+        Err(what_happened) => {
+            return Err(format!("failed to read {}: {}", path, what_happened));
+        }
+    };
+    let summary = backup::import(&document, mode)?;
+    println!("{}", summary);
+    Ok(())
+}
+
 fn main() {
-    let alarm_heap = match file::load() {
-        Ok(heap) => heap,
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        [] => {
+            run_bot();
+            Ok(())
+        },
+        ["export", path] => export(path),
+        ["import", path] | ["import", path, "--merge"] => import(path, backup::ImportMode::Merge),
+        ["import", path, "--replace"] => import(path, backup::ImportMode::Replace),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(what_happened) = result {
+        eprintln!("{}", what_happened);
+        std::process::exit(1);
+    }
+}
+
+// optional parts of stoat the bot makes use of, and what goes wrong without them.
+const FEATURES_USED: &[(&str, &str)] = &[
+    ("january", "masquerade avatars may not show, since january is what fetches images from other sites")
+];
+
+// keeps trying if the api can't be reached or is broken, since that's probably temporary.
+// anything else (like the api refusing BOT_TOKEN) is given back.
+fn keep_trying<T>(what: &str, mut request: impl FnMut() -> Result<T, http::Error>) -> Result<T, http::Error> {
+    const RETRY_EVERY: Duration = Duration::from_secs(10);
+    loop {
+        match stoat_api::wait_if_rate_limited(&mut request) {
+            // the api answered, just not with anything the bot understands.  asking again won't change that.
+            Err(what_happened @ http::Error::UnexpectedBody(_)) => {
+                return Err(what_happened);
+            },
+            Err(what_happened) if !what_happened.is_permanent() && what_happened.status().is_none_or(|status| status >= 500) => {
+                println!("{}\nfailed to {}, trying again in {}s", what_happened, what, RETRY_EVERY.as_secs());
+                std::thread::sleep(RETRY_EVERY);
+            },
+            result => {
+                return result;
+            }
+        }
+    }
+}
+
+// asks the api where the event websocket is.
+fn discover() -> Result<String, String> {
+    let root = match keep_trying("reach the api", stoat_api::fetch_root) {
+        Ok(root) => root,
+        Err(what_happened) => {
+            return Err(format!("{}\nfailed to read the api root.  make sure API_URL in config.rs is right", what_happened));
+        }
+    };
+    println!("connected to stoat {}, events at {}", root.version, root.ws);
+    for (feature, without_it) in FEATURES_USED {
+        if root.features.get(*feature) == Some(&false) {
+            println!("warning: {} is turned off on this server, so {}", feature, without_it);
+        }
+    }
+    Ok(root.ws)
+}
+
+// finds out the bot's own user id, which also checks BOT_TOKEN is right.
+fn identify() -> Result<String, String> {
+    match keep_trying("find out who the bot is", stoat_api::fetch_self) {
+        Ok(user) => {
+            if !user.is_bot {
+                println!("warning: BOT_TOKEN belongs to {}, who isn't a bot", user.username);
+            }
+            println!("logged in as {} ({})", user.name(), user.id);
+            Ok(user.id)
+        },
+        Err(what_happened) if what_happened.status() == Some(401) => {
+            Err(format!("{}\nthe api refused BOT_TOKEN.  make sure it's set correctly in config.rs", what_happened))
+        },
+        Err(what_happened) => {
+            Err(format!("{}\nfailed to find out who the bot is", what_happened))
+        }
+    }
+}
+
+fn run_bot() {
+    let events_url = match discover() {
+        Ok(events_url) => events_url,
+        Err(message) => {
+            println!("{message}\nquitting.");
+            return;
+        }
+    };
+    let bot_id = match identify() {
+        Ok(bot_id) => bot_id,
+        Err(message) => {
+            println!("{message}\nquitting.");
+            return;
+        }
+    };
+    // held until the bot stops, so an import or export can't change the alarms under it.
+    let _lock = match store::lock() {
+        Ok(lock) => lock,
+        Err(message) => {
+            println!("{message}\nquitting.");
+            return;
+        }
+    };
+    let alarm_heap = match store::load() {
+        Ok((heap, summary)) => {
+            println!("{}", summary);
+            heap
+        },
         Err(message) => {
             println!("alarm heap failed to load.\n{message}\nquitting.");
             return;
         }
     };
     let alarm_heap = Arc::new(Mutex::new(alarm_heap));
-    let listener_handle = event_listener::start_listening(alarm_heap.clone());
+    let listener_handle = event_listener::start_listening(bot_id.clone(), events_url, alarm_heap.clone());
+    main_loop(&listener_handle, &bot_id, &alarm_heap);
+    if let Err(what_happened) = last_seen::flush() {
+        println!("{}", what_happened);
+    }
+}
+
+fn main_loop(listener_handle: &JoinHandle<()>, bot_id: &str, alarm_heap: &Arc<Mutex<AlarmHeap>>) {
+    const PRUNE_EVERY: Duration = Duration::from_secs(60 * 60);
+    let mut last_pruned: Option<Instant> = None;
     while !listener_handle.is_finished() {
+        if last_pruned.is_none_or(|last_pruned| last_pruned.elapsed() >= PRUNE_EVERY) {
+            if let Err(what_happened) = history::prune() {
+                println!("main loop: {}", what_happened);
+            }
+            last_pruned = Some(Instant::now());
+        }
+        if let Err(what_happened) = last_seen::save_if_due() {
+            println!("main loop: {}", what_happened);
+        }
         let maybe_alarm = {
             let Ok(mut heap_lock) = alarm_heap.lock() else {
                 println!("main loop: alarm_heap mutex has been poisoned.  ending.");
@@ -31,18 +210,15 @@ fn main() {
             heap_lock.pop_if_timeup(&Utc::now().naive_utc())
         };
         if let Some(alarm) = maybe_alarm {
-            const ALARM_CLOCK: &str = "%E2%8F%B0";
-            if let Err(what_happened) = stoat_api::react(&alarm.channel_id, &alarm.message_id, ALARM_CLOCK) {
-                println!("main loop: {}\ncaused by reacting to {:?}", what_happened, &alarm);
-            }
-            if let Err(what_happened) = stoat_api::post_alarm(&alarm) {
-                println!("main loop: {}\ncaused by reacting to {:?}", what_happened, &alarm);
-            }
-            if let Err(what_happened) = file::delete(&alarm) {
-                println!("main loop: {}", what_happened);
+            if let Err(what_happened) = outbox::deliver(alarm, bot_id, alarm_heap) {
+                println!("main loop: {}.  ending.", what_happened);
+                return;
             }
         } else {
             std::thread::sleep(Duration::from_secs(1));
         }
     }
+    // it reconnects by itself when the connection drops,
+    // so this only happens if it panicked.
+    println!("main loop: event listener has stopped.  ending.");
 }
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use crate::alarm::Alarm;
+use crate::alarm_heap::AlarmHeap;
+use crate::cache;
+use crate::config;
+use crate::history;
+use crate::http::Error;
+use crate::json;
+use crate::json::IntOrFloat;
+use crate::json::Value;
+use crate::settings;
+use crate::stoat_api;
+use crate::stoat_api::Message;
+use crate::store;
+use crate::ulid;
+
+// posting an alarm can fail.  when it does, the alarm stays saved,
+// with how many times it's failed and when to try again,
+// so retries carry on across restarts.
+// an alarm is only removed from the store once it's been posted,
+// or once it's clear it never will be.  those last ones are written to
+// DEAD_LETTER_DIR as <message id>.json, for someone to look into.
+
+fn backoff(attempts: u32) -> Duration {
+    const FIRST_RETRY: Duration = Duration::from_secs(30);
+    const LONGEST_RETRY: Duration = Duration::from_secs(60 * 60);
+    FIRST_RETRY.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1))).min(LONGEST_RETRY)
+}
+
+fn dead_letter(alarm: &Alarm, reason: &str) -> Result<(), String> {
+    if !ulid::is_valid(&alarm.message_id) {
+        return Err(format!("refusing to dead letter {:?}, it isn't a valid id", alarm.message_id));
+    }
+    let dead_letter_dir = Path::new(config::DEAD_LETTER_DIR);
+    if let Err(what_happened) = std::fs::create_dir_all(dead_letter_dir) {
+        return Err(format!("failed to create dead letter folder {}: {}", dead_letter_dir.display(), what_happened));
+    }
+    let dead_letter = Value::Object(HashMap::from([
+        ("alarm".to_string(), alarm.to_json()),
+        ("reason".to_string(), Value::String(reason.to_string())),
+        ("failed".to_string(), Value::Number(IntOrFloat::Int(Utc::now().timestamp_millis())))
+    ]));
+    let path = dead_letter_dir.join(format!("{}.json", alarm.message_id));
+    if let Err(what_happened) = std::fs::write(&path, json::stringify_pretty(&dead_letter)) {
+        return Err(format!("failed to write dead letter {}: {}", path.display(), what_happened));
+    }
+    println!("outbox: gave up on {}, moved it to {}: {}", alarm.message_id, path.display(), reason);
+    Ok(())
+}
+
+// lets whoever set the alarm know it won't be posted, if the bot already has a dm open with them.
+// opening one just for this isn't worth it, the dead letter's there either way.
+fn tell_author(alarm: &Alarm) {
+    let Some(dm_channel) = alarm.author.as_deref().and_then(cache::dm_channel) else {
+        return;
+    };
+    let channel = cache::channel(&alarm.channel_id);
+    let channel_name = channel.as_ref().and_then(|channel| channel.name.clone()).unwrap_or_else(|| alarm.channel_id.to_string());
+    let server_name = channel.and_then(|channel| channel.server).and_then(|server_id| cache::server_name(&server_id));
+    let place = match server_name {
+        Some(server_name) => format!("#{} in {}", channel_name, server_name),
+        None => format!("#{}", channel_name)
+    };
+    let content = format!("your alarm couldn't be posted in {}: {}", place, alarm.what);
+    if let Err(what_happened) = stoat_api::post_message(&dm_channel, &content) {
+        println!("outbox: {}\ncaused by telling the author about {:?}", what_happened, alarm);
+    }
+}
+
+fn give_up(alarm: &Alarm, reason: String) {
+    if let Err(what_happened) = dead_letter(alarm, &reason) {
+        // better to leave it saved than to lose it.
+        println!("outbox: {}", what_happened);
+        return;
+    }
+    tell_author(alarm);
+    if let Err(what_happened) = history::record(alarm, history::Status::Failed(reason), None) {
+        println!("outbox: {}", what_happened);
+    }
+    if let Err(what_happened) = store::fire(alarm) {
+        println!("outbox: {}", what_happened);
+    }
+}
+
+fn delivered(alarm: &Alarm, posted: Option<&Message>) {
+    if let Err(what_happened) = history::record(alarm, history::Status::Delivered, posted) {
+        println!("outbox: {}", what_happened);
+    }
+    if let Err(what_happened) = store::fire(alarm) {
+        println!("outbox: {}", what_happened);
+    }
+}
+
+fn push(alarm: Alarm, alarm_heap: &Mutex<AlarmHeap>) -> Result<(), String> {
+    let Ok(mut heap_lock) = alarm_heap.lock() else {
+        return Err("alarm_heap mutex has been poisoned".to_string());
+    };
+    heap_lock.push(alarm);
+    Ok(())
+}
+
+// puts the alarm back until the rate limit is over.
+// that's not the alarm's fault, so it doesn't count as a failed attempt.
+// it isn't saved either: after a restart, the rate limit's long gone.
+fn wait_for_rate_limit(mut alarm: Alarm, wait: Duration, alarm_heap: &Mutex<AlarmHeap>) -> Result<(), String> {
+    alarm.retry_at = Some(Utc::now().naive_utc() + wait);
+    push(alarm, alarm_heap)
+}
+
+// a 401 means BOT_TOKEN isn't accepted any more, so nothing's going to get posted.
+// the alarm's left saved as it was, for when the bot's restarted with a working token.
+fn token_refused(what_happened: &Error) -> String {
+    format!("{}\nthe api refused BOT_TOKEN.  make sure it's set correctly in config.rs", what_happened)
+}
+
+// fails if the alarm heap's mutex is poisoned, or the api refused BOT_TOKEN.
+// either way the bot can't carry on.
+pub fn deliver(mut alarm: Alarm, bot_id: &str, alarm_heap: &Mutex<AlarmHeap>) -> Result<(), String> {
+    let server_settings = settings::for_channel(&alarm.channel_id);
+    let mut embed = server_settings.embed.as_ref();
+    let mut masquerade = server_settings.masquerade.as_ref();
+    // no point asking for what the cache says the bot isn't allowed.
+    // if the cache doesn't know, post_alarm finds out from the api instead.
+    if let Some(permissions) = cache::channel_permissions(&alarm.channel_id, bot_id) {
+        if permissions & cache::SEND_EMBEDS == 0 {
+            embed = None;
+        }
+        if permissions & cache::MASQUERADE == 0 {
+            masquerade = None;
+        }
+    }
+    if !alarm.reacted {
+        match stoat_api::react(&alarm.channel_id, &alarm.message_id, &server_settings.fired_reaction) {
+            Ok(()) => {},
+            Err(Error::RateLimited(wait)) => {
+                return wait_for_rate_limit(alarm, wait, alarm_heap);
+            },
+            Err(what_happened) if what_happened.status() == Some(401) => {
+                return Err(token_refused(&what_happened));
+            },
+            Err(what_happened) => {
+                println!("outbox: {}\ncaused by reacting to {:?}", what_happened, &alarm);
+            }
+        }
+        // saved along with the alarm if posting fails, so it isn't reacted to again.
+        alarm.reacted = true;
+    }
+
+    let what_happened = match stoat_api::post_alarm(&alarm, embed, masquerade) {
+        Ok(posted) => {
+            delivered(&alarm, Some(&posted));
+            return Ok(());
+        },
+        // it was posted, the response just couldn't be read.
+        // retrying would post it twice.
+        Err(Error::UnexpectedBody(what_happened)) => {
+            println!("outbox: {}\ncaused by posting {:?}", what_happened, &alarm);
+            delivered(&alarm, None);
+            return Ok(());
+        },
+        // an earlier try went through after all, it just looked like it failed.
+        Err(what_happened) if stoat_api::already_sent(&what_happened) => {
+            println!("outbox: {:?} was already posted", &alarm);
+            delivered(&alarm, None);
+            return Ok(());
+        },
+        Err(Error::RateLimited(wait)) => {
+            return wait_for_rate_limit(alarm, wait, alarm_heap);
+        },
+        Err(what_happened) if what_happened.status() == Some(401) => {
+            return Err(token_refused(&what_happened));
+        },
+        Err(what_happened) => what_happened
+    };
+    println!("outbox: {}\ncaused by posting {:?}", what_happened, &alarm);
+    if what_happened.is_permanent() {
+        give_up(&alarm, what_happened.to_string());
+        return Ok(());
+    }
+
+    alarm.attempts += 1;
+    if alarm.attempts >= config::MAX_DELIVERY_ATTEMPTS {
+        give_up(&alarm, format!("failed {} times, last with: {}", alarm.attempts, what_happened));
+        return Ok(());
+    }
+    alarm.retry_at = Some(Utc::now().naive_utc() + backoff(alarm.attempts));
+    if let Err(what_happened) = store::update(&alarm) {
+        println!("outbox: {}", what_happened);
+    }
+    push(alarm, alarm_heap)
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::json;
+use crate::json::Value;
+use crate::ulid;
+
+// a small json object kept in a file, keyed by stoat ids:
+// {"<id>":<value>, ...}
+// it's loaded the first time it's needed, then kept in memory,
+// and only written back when asked to.
+// entries with a key that isn't an id, or a value from_json won't take, are dropped when loading.
+pub struct SavedMap<T> {
+    path: &'static str,
+    from_json: fn(&Value) -> Option<T>,
+    to_json: fn(&T) -> Value,
+    // None until it's been loaded.
+    entries: Option<HashMap<String, T>>
+}
+
+impl<T> SavedMap<T> {
+    pub const fn new(path: &'static str, from_json: fn(&Value) -> Option<T>, to_json: fn(&T) -> Value) -> Self {
+        Self {
+            path,
+            from_json,
+            to_json,
+            entries: None
+        }
+    }
+
+    fn read_file(&self) -> HashMap<String, T> {
+        let mut entries = HashMap::new();
+        let path = Path::new(self.path);
+        if std::fs::exists(path).ok().is_none_or(|exists| !exists) {
+            return entries;
+        }
+        let Ok(file_bytes) = std::fs::read(path) else {
+            println!("saved map: failed to read {}, starting over", path.display());
+            return entries;
+        };
+        let Ok((Value::Object(saved), _)) = json::parse_value(&file_bytes, 0) else {
+            println!("saved map: {} is not a json object, starting over", path.display());
+            return entries;
+        };
+        for (id, value) in saved {
+            if ulid::is_valid(&id)
+                && let Some(value) = (self.from_json)(&value)
+            {
+                entries.insert(id, value);
+            }
+        }
+        entries
+    }
+
+    pub fn entries(&mut self) -> &mut HashMap<String, T> {
+        if self.entries.is_none() {
+            self.entries = Some(self.read_file());
+        }
+        self.entries.get_or_insert_default()
+    }
+
+    pub fn save(&mut self) -> Result<(), String> {
+        let to_json = self.to_json;
+        let saved = Value::Object(self.entries().iter()
+            .map(|(id, value)| (id.to_string(), to_json(value)))
+            .collect());
+        // written to the side and renamed over, so a crash can't leave half a file.
+        let temp_path = format!("{}.tmp", self.path);
+        if let Err(what_happened) = std::fs::write(&temp_path, json::stringify(&saved)) {
+            return Err(format!("failed to write {}: {}", temp_path, what_happened));
+        }
+        if let Err(what_happened) = std::fs::rename(&temp_path, self.path) {
+            return Err(format!("failed to replace {}: {}", self.path, what_happened));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::IntOrFloat;
+
+    fn number(value: &Value) -> Option<i64> {
+        match value {
+            Value::Number(IntOrFloat::Int(number)) if *number >= 0 => Some(*number),
+            _ => None
+        }
+    }
+
+    fn number_to_json(number: &i64) -> Value {
+        Value::Number(IntOrFloat::Int(*number))
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let path = std::env::temp_dir().join(format!("thats-quite-alarming-saved-map-{}.json", std::process::id()));
+        let path: &'static str = path.to_str().unwrap().to_string().leak();
+        std::fs::write(path, r#"{"01ARZ3NDEKTSV4RRFFQ69G5FAV":1,"not an id":2,"01BX5ZZKBKACTAV9WEVGEMMVRZ":-3}"#).unwrap();
+
+        let mut saved_map = SavedMap::new(path, number, number_to_json);
+        assert_eq!(saved_map.entries().clone(), HashMap::from([("01ARZ3NDEKTSV4RRFFQ69G5FAV".to_string(), 1)]));
+        saved_map.entries().insert("01BX5ZZKBKACTAV9WEVGEMMVRZ".to_string(), 4);
+        saved_map.save().unwrap();
+
+        let mut reloaded = SavedMap::new(path, number, number_to_json);
+        assert_eq!(reloaded.entries().get("01BX5ZZKBKACTAV9WEVGEMMVRZ"), Some(&4));
+        assert_eq!(reloaded.entries().len(), 2);
+        std::fs::remove_file(path).unwrap();
+
+        // a file that isn't there is just empty.
+        assert!(SavedMap::new(path, number, number_to_json).entries().is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use crate::cache;
+use crate::config;
+use crate::json;
+use crate::json::Value;
+use crate::stoat_api;
+use crate::stoat_api::EmbedStyle;
+use crate::stoat_api::Emoji;
+use crate::stoat_api::Masquerade;
+use crate::ulid;
+
+// each server can have its own settings, in SETTINGS_DIR/<server id>.json.
+// every field is optional, anything left out uses the default:
+// {
+//     "confirm_reaction": "✅",
+//     "fired_reaction": "⏰",
+//     "embed": false,
+//     "embed_colour": "#f5a623",
+//     "masquerade_name": "Alarm Clock",
+//     "masquerade_avatar": "https://example.com/clock.png"
+// }
+// reactions can be a unicode emoji, or the id of one of the server's custom emoji.
+// with "embed" on, alarms are posted as an embed showing when they were set for,
+// instead of as plain text.  "embed_colour" only matters for embeds, and can be a colour name,
+// a hex code like "#f5a623", "rgb(245, 166, 35)", or a css variable like "var(--accent)".
+// setting either masquerade field posts alarms under that name or avatar,
+// which needs the bot to have the masquerade permission.
+// each file is read once and kept, and only read again once it's been changed,
+// so edits still apply right away.
+
+#[derive(Clone, Debug)]
+pub struct ServerSettings {
+    // reacted to a message when it sets an alarm.
+    pub confirm_reaction: Emoji,
+    // reacted to a message when its alarm goes off.
+    pub fired_reaction: Emoji,
+    // None posts alarms as plain text.
+    pub embed: Option<EmbedStyle>,
+    pub masquerade: Option<Masquerade>
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            confirm_reaction: Emoji::Unicode("✅".to_string()),
+            fired_reaction: Emoji::Unicode("⏰".to_string()),
+            embed: None,
+            masquerade: None
+        }
+    }
+}
+
+// what's known about which server a channel is in, so it's only looked up once.
+enum ChannelServer {
+    // None means the channel isn't in a server.
+    Found(Option<String>),
+    // looking it up failed, so it gets the default settings until it's worth asking again.
+    Failed(Instant)
+}
+
+// how long to wait before asking which server a channel is in again, after it failed.
+const RETRY_LOOKUP_AFTER: Duration = Duration::from_secs(5 * 60);
+
+static CHANNEL_SERVERS: Mutex<Option<HashMap<String, ChannelServer>>> = Mutex::new(None);
+
+// a server's settings, as they were when its file was last read.
+struct Loaded {
+    // when the file was last changed, or None if there isn't one,
+    // to tell when it needs reading again.
+    modified: Option<SystemTime>,
+    settings: ServerSettings
+}
+
+static SERVER_SETTINGS: Mutex<Option<HashMap<String, Loaded>>> = Mutex::new(None);
+
+fn emoji_setting(settings: &HashMap<String, Value>, name: &str, server_id: &str) -> Option<Emoji> {
+    match settings.get(name) {
+        Some(Value::String(emoji)) => {
+            let emoji = Emoji::parse(emoji);
+            if emoji.is_none() {
+                println!("settings: {} for server {} is empty, using the default", name, server_id);
+            }
+            emoji
+        },
+        Some(_) => {
+            println!("settings: {} for server {} is not a string, using the default", name, server_id);
+            None
+        },
+        None => None
+    }
+}
+
+fn string_setting(settings: &HashMap<String, Value>, name: &str, server_id: &str) -> Option<String> {
+    match settings.get(name) {
+        Some(Value::String(text)) if text.trim().is_empty() => {
+            println!("settings: {} for server {} is empty, using the default", name, server_id);
+            None
+        },
+        Some(Value::String(text)) => Some(text.trim().to_string()),
+        Some(_) => {
+            println!("settings: {} for server {} is not a string, using the default", name, server_id);
+            None
+        },
+        None => None
+    }
+}
+
+// the colours stoat accepts in an embed.  anything else gets the whole message refused,
+// so it's better to catch it here.
+fn is_colour(text: &str) -> bool {
+    const LONGEST_COLOUR: usize = 128;
+    let text = text.to_ascii_lowercase();
+    if text.len() > LONGEST_COLOUR {
+        return false;
+    }
+    if let Some(hex) = text.strip_prefix('#') {
+        return !hex.is_empty() && hex.chars().all(|character| character.is_ascii_hexdigit());
+    }
+    if let Some(variable) = text.strip_prefix("var(--").and_then(|text| text.strip_suffix(')')) {
+        return !variable.is_empty() && variable.chars().all(|character| character.is_ascii_alphanumeric() || character == '-');
+    }
+    if let Some(numbers) = text.strip_prefix("rgba(").or_else(|| text.strip_prefix("rgb(")).and_then(|text| text.strip_suffix(')')) {
+        return !numbers.is_empty() && numbers.chars().all(|character| character.is_ascii_digit() || character == ',' || character == ' ');
+    }
+    text.chars().all(|character| character.is_ascii_lowercase() || character == ' ')
+}
+
+fn colour_setting(settings: &HashMap<String, Value>, name: &str, server_id: &str) -> Option<String> {
+    let colour = string_setting(settings, name, server_id)?;
+    if !is_colour(&colour) {
+        println!("settings: {} for server {} is not a colour stoat accepts, using the default", name, server_id);
+        return None;
+    }
+    Some(colour)
+}
+
+fn bool_setting(settings: &HashMap<String, Value>, name: &str, server_id: &str) -> Option<bool> {
+    match settings.get(name) {
+        Some(Value::Boolean(on)) => Some(*on),
+        Some(_) => {
+            println!("settings: {} for server {} is not true or false, using the default", name, server_id);
+            None
+        },
+        None => None
+    }
+}
+
+fn path(server_id: &str) -> PathBuf {
+    Path::new(config::SETTINGS_DIR).join(format!("{}.json", server_id))
+}
+
+pub fn load(server_id: &str) -> ServerSettings {
+    let mut server_settings = ServerSettings::default();
+    if !ulid::is_valid(server_id) {
+        return server_settings;
+    }
+    let path = path(server_id);
+    if std::fs::exists(&path).ok().is_none_or(|exists| !exists) {
+        return server_settings;
+    }
+    let Ok(file_bytes) = std::fs::read(&path) else {
+        println!("settings: failed to read {}, using the defaults", path.display());
+        return server_settings;
+    };
+    let Ok((Value::Object(settings), _)) = json::parse_value(&file_bytes, 0) else {
+        println!("settings: {} is not a json object, using the defaults", path.display());
+        return server_settings;
+    };
+
+    if let Some(confirm_reaction) = emoji_setting(&settings, "confirm_reaction", server_id) {
+        server_settings.confirm_reaction = confirm_reaction;
+    }
+    if let Some(fired_reaction) = emoji_setting(&settings, "fired_reaction", server_id) {
+        server_settings.fired_reaction = fired_reaction;
+    }
+    if bool_setting(&settings, "embed", server_id) == Some(true) {
+        server_settings.embed = Some(EmbedStyle {
+            colour: colour_setting(&settings, "embed_colour", server_id)
+        });
+    }
+    let masquerade = Masquerade {
+        name: string_setting(&settings, "masquerade_name", server_id),
+        avatar: string_setting(&settings, "masquerade_avatar", server_id)
+    };
+    if masquerade.name.is_some() || masquerade.avatar.is_some() {
+        server_settings.masquerade = Some(masquerade);
+    }
+    server_settings
+}
+
+// like load, but only reads the file if it's changed since it was last read.
+fn load_if_changed(server_id: &str) -> ServerSettings {
+    let modified = std::fs::metadata(path(server_id)).and_then(|metadata| metadata.modified()).ok();
+    if let Ok(all_settings) = SERVER_SETTINGS.lock()
+        && let Some(loaded) = all_settings.as_ref().and_then(|all_settings| all_settings.get(server_id))
+        && loaded.modified == modified
+    {
+        return loaded.settings.clone();
+    }
+    let server_settings = load(server_id);
+    if let Ok(mut all_settings) = SERVER_SETTINGS.lock() {
+        all_settings.get_or_insert_default().insert(server_id.to_string(), Loaded {
+            modified,
+            settings: server_settings.clone()
+        });
+    }
+    server_settings
+}
+
+fn server_of(channel_id: &str) -> Option<String> {
+    if let Ok(channel_servers) = CHANNEL_SERVERS.lock() {
+        match channel_servers.as_ref().and_then(|channel_servers| channel_servers.get(channel_id)) {
+            Some(ChannelServer::Found(server)) => {
+                return server.clone();
+            },
+            Some(ChannelServer::Failed(when)) if when.elapsed() < RETRY_LOOKUP_AFTER => {
+                return None;
+            },
+            _ => {}
+        }
+    }
+    // the websocket tells the bot about every channel it can see, so this is usually enough.
+    if let Some(channel) = cache::channel(channel_id) {
+        return channel.server;
+    }
+
+    let (found, server) = match stoat_api::wait_if_rate_limited(|| stoat_api::fetch_channel(channel_id)) {
+        Ok(channel) => (ChannelServer::Found(channel.server.clone()), channel.server),
+        Err(what_happened) => {
+            println!("settings: {}\nfailed to find the server for channel {}, using the default settings", what_happened, channel_id);
+            (ChannelServer::Failed(Instant::now()), None)
+        }
+    };
+    if let Ok(mut channel_servers) = CHANNEL_SERVERS.lock() {
+        channel_servers.get_or_insert_default().insert(channel_id.to_string(), found);
+    }
+    server
+}
+
+// the settings for whichever server the channel is in,
+// or the defaults if it's not in one.
+pub fn for_channel(channel_id: &str) -> ServerSettings {
+    match server_of(channel_id) {
+        Some(server_id) => load_if_changed(&server_id),
+        None => ServerSettings::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colours() {
+        assert!(is_colour("#f5a623"));
+        assert!(is_colour("#FFF"));
+        assert!(is_colour("orange"));
+        assert!(is_colour("Light Blue"));
+        assert!(is_colour("rgb(245, 166, 35)"));
+        assert!(is_colour("rgba(245,166,35,0)"));
+        assert!(is_colour("var(--accent-2)"));
+
+        assert!(!is_colour("#"));
+        assert!(!is_colour("#f5a62g"));
+        assert!(!is_colour("orange!"));
+        assert!(!is_colour("rgb()"));
+        assert!(!is_colour("rgb(0.5, 0, 0)"));
+        assert!(!is_colour("hsl(30, 100%, 50%)"));
+        assert!(!is_colour("var(accent)"));
+        assert!(!is_colour(&"a".repeat(129)));
+    }
+}
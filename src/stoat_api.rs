@@ -1,58 +1,348 @@
-use std::io::Read;
-use std::io::Write;
-use std::net::TcpStream;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::hash::RandomState;
+use std::sync::Mutex;
 use std::time::Duration;
-
-use native_tls::TlsConnector;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use crate::alarm::Alarm;
+use crate::cache;
 use crate::config;
+use crate::http;
+use crate::http::Error;
+use crate::http::Response;
+use crate::http::Server;
 use crate::json;
 use crate::json::Value;
+use crate::ulid;
+use crate::url;
+use crate::url::Segment;
 
-fn sanitize(input: &str) -> String {
-    input.replace("\\", "\\\\").replace("\"", "\\\"")
+// a message as the api returns it.
+#[derive(Debug)]
+pub struct Message {
+    pub id: String,
+    pub channel: String,
+    pub author: String,
+    // empty if the message is only embeds or attachments.
+    pub content: String
 }
 
-fn send(request: &[u8]) -> Result<(), String> {
-    loop {
-        let mut stream = {
-            let Ok(connector) = TlsConnector::new() else {
-                return Err("https: failed to create tls connector".to_string());
-            };
-            let Ok(tcp_stream) = TcpStream::connect(config::HTTP_SOCKET) else {
-                return Err("https: failed to create tcp stream".to_string());
-            };
-            let Ok(tls_stream) = connector.connect(config::HTTP_ENDPOINT, tcp_stream) else {
-                return Err("https: failed to create tls stream".to_string());
-            };
-            tls_stream
+impl Message {
+    pub fn from_json(message: &HashMap<String, Value>) -> Option<Self> {
+        let Some(Value::String(id)) = message.get("_id") else {
+            return None;
         };
-
-        let Ok(()) = stream.write_all(request) else {
-            return Err("https: failed to write request".to_string());
+        let Some(Value::String(channel)) = message.get("channel") else {
+            return None;
+        };
+        let Some(Value::String(author)) = message.get("author") else {
+            return None;
         };
-        let Ok(()) = stream.flush() else {
-            return Err("https: failed to flush stream".to_string());
+        let content = match message.get("content") {
+            Some(Value::String(content)) => content.to_string(),
+            _ => String::new()
         };
+        Some(Self {
+            id: id.to_string(),
+            channel: channel.to_string(),
+            author: author.to_string(),
+            content
+        })
+    }
+}
 
-        let mut response = vec![];
-        let Ok(_response_length) = stream.read_to_end(&mut response) else {
-            return Err("https: failed to read response".to_string());
+#[derive(Debug)]
+pub struct Channel {
+    pub id: String,
+    // like "TextChannel", "DirectMessage", "Group" or "SavedMessages".
+    pub channel_type: String,
+    // None for dms and saved messages.
+    pub name: Option<String>,
+    // None for anything that isn't in a server.
+    pub server: Option<String>
+}
+
+impl Channel {
+    pub fn from_json(channel: &HashMap<String, Value>) -> Option<Self> {
+        let Some(Value::String(id)) = channel.get("_id") else {
+            return None;
+        };
+        let Some(Value::String(channel_type)) = channel.get("channel_type") else {
+            return None;
+        };
+        let name = match channel.get("name") {
+            Some(Value::String(name)) => Some(name.to_string()),
+            _ => None
         };
-        std::mem::drop(stream);
+        let server = match channel.get("server") {
+            Some(Value::String(server)) => Some(server.to_string()),
+            _ => None
+        };
+        Some(Self {
+            id: id.to_string(),
+            channel_type: channel_type.to_string(),
+            name,
+            server
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct User {
+    pub id: String,
+    pub username: String,
+    pub discriminator: String,
+    pub display_name: Option<String>,
+    pub is_bot: bool
+}
 
-        let Ok(response) = String::from_utf8(response) else {
-            return Err("https: response is not valid utf8".to_string());
+impl User {
+    pub fn from_json(user: &HashMap<String, Value>) -> Option<Self> {
+        let Some(Value::String(id)) = user.get("_id") else {
+            return None;
+        };
+        let Some(Value::String(username)) = user.get("username") else {
+            return None;
         };
+        let discriminator = match user.get("discriminator") {
+            Some(Value::String(discriminator)) => discriminator.to_string(),
+            _ => String::new()
+        };
+        let display_name = match user.get("display_name") {
+            Some(Value::String(display_name)) => Some(display_name.to_string()),
+            _ => None
+        };
+        // bots have a "bot" object saying who owns them, people don't.
+        let is_bot = matches!(user.get("bot"), Some(Value::Object(_)));
+        Some(Self {
+            id: id.to_string(),
+            username: username.to_string(),
+            discriminator,
+            display_name,
+            is_bot
+        })
+    }
+
+    // what the user shows up as.
+    pub fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.username)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Emoji {
+    Unicode(String),
+    // one of a server's custom emoji, by its id.
+    Custom(String)
+}
+
+impl Emoji {
+    // takes either a unicode emoji, or a custom emoji's id
+    // (with or without the colons around it, like in message text).
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let maybe_id = text.strip_prefix(':').and_then(|id| id.strip_suffix(':')).unwrap_or(text);
+        if ulid::is_valid(maybe_id) {
+            Some(Self::Custom(maybe_id.to_string()))
+        } else if text.is_empty() {
+            None
+        } else {
+            Some(Self::Unicode(text.to_string()))
+        }
+    }
+
+    fn segment(&self) -> Segment<'_> {
+        match self {
+            Self::Unicode(emoji) => Segment::Text(emoji),
+            Self::Custom(id) => Segment::Id(id)
+        }
+    }
+}
+
+fn parse_body<T>(response: &Response, from_json: fn(&HashMap<String, Value>) -> Option<T>, what: &str) -> Result<T, Error> {
+    let Ok((Value::Object(object), _)) = json::parse_value(&response.body, 0) else {
+        return Err(Error::UnexpectedBody(format!("the {} in the response is not a json object", what)));
+    };
+    let Some(parsed) = from_json(&object) else {
+        return Err(Error::UnexpectedBody(format!("the {} in the response is missing fields", what)));
+    };
+    Ok(parsed)
+}
+
+fn parse_message(response: &Response) -> Result<Message, Error> {
+    parse_body(response, Message::from_json, "message")
+}
 
-        // wait and retry if the rate limit has been hit.
-        if response.split_once("\r\n").is_some_and(|(first_line, _everything_after)|
-            first_line.contains("429 Too Many Requests")
-        ) {
-            let time_to_sleep = if
-                let Some((_headers, body)) = response.split_once("\r\n\r\n")
-                && let Ok((Value::Object(response_json), _)) = json::parse_value(body.as_bytes(), 0)
+// stoat tells us how much of each rate limit bucket is left in every response,
+// so requests that would go over can be held back before they're sent,
+// instead of only finding out from a 429 afterwards.
+//   X-RateLimit-Bucket: which bucket the route belongs to
+//   X-RateLimit-Remaining: how many requests are left in the bucket
+//   X-RateLimit-Reset-After: how many milliseconds until the bucket refills
+struct Bucket {
+    remaining: u32,
+    resets_at: Instant
+}
+
+#[derive(Default)]
+struct RateLimits {
+    // route, from route(), to the name of its bucket.
+    route_buckets: HashMap<String, String>,
+    buckets: HashMap<String, Bucket>
+}
+
+static RATE_LIMITS: Mutex<Option<RateLimits>> = Mutex::new(None);
+
+// "<method> <path>", with the ids and text in the path swapped for placeholders,
+// like "POST /channels/{id}/messages".  that way what's learned about a bucket
+// from one channel applies to the same request in every other channel too.
+fn route(method: &str, path: &[Segment]) -> String {
+    let mut route = method.to_string();
+    route.push(' ');
+    for segment in path {
+        route.push('/');
+        route.push_str(match segment {
+            Segment::Literal(literal) => literal,
+            Segment::Id(_) => "{id}",
+            Segment::Text(_) => "{text}"
+        });
+    }
+    route
+}
+
+// if the route's bucket is used up, how long until it refills.
+// otherwise, takes one request out of the bucket.
+fn reserve(route: &str) -> Option<Duration> {
+    let mut rate_limits = RATE_LIMITS.lock().ok()?;
+    let rate_limits = rate_limits.get_or_insert_default();
+    let bucket_name = rate_limits.route_buckets.get(route)?;
+    let bucket = rate_limits.buckets.get_mut(bucket_name)?;
+    let now = Instant::now();
+    if bucket.resets_at <= now {
+        // it's refilled, but we don't know by how much until the next response.
+        rate_limits.buckets.remove(bucket_name);
+        return None;
+    }
+    if bucket.remaining == 0 {
+        return Some(bucket.resets_at - now);
+    }
+    bucket.remaining -= 1;
+    None
+}
+
+fn update_rate_limits(route: &str, response: &Response) {
+    let (Some(bucket_name), Some(Ok(remaining)), Some(Ok(reset_after))) = (
+        response.header("x-ratelimit-bucket"),
+        response.header("x-ratelimit-remaining").map(str::parse::<u32>),
+        response.header("x-ratelimit-reset-after").map(str::parse::<u64>)
+    ) else {
+        return;
+    };
+    let Ok(mut rate_limits) = RATE_LIMITS.lock() else {
+        return;
+    };
+    let rate_limits = rate_limits.get_or_insert_default();
+    rate_limits.route_buckets.insert(route.to_string(), bucket_name.to_string());
+    rate_limits.buckets.insert(bucket_name.to_string(), Bucket {
+        remaining,
+        resets_at: Instant::now() + Duration::from_millis(reset_after)
+    });
+}
+
+// the rate limit was hit anyway, so the bucket is empty for now.
+fn exhaust_bucket(route: &str, retry_after: Duration) {
+    let Ok(mut rate_limits) = RATE_LIMITS.lock() else {
+        return;
+    };
+    let rate_limits = rate_limits.get_or_insert_default();
+    let Some(bucket_name) = rate_limits.route_buckets.get(route) else {
+        return;
+    };
+    rate_limits.buckets.insert(bucket_name.to_string(), Bucket {
+        remaining: 0,
+        resets_at: Instant::now() + retry_after
+    });
+}
+
+// where the api is, and the path it's under (empty if it's at the root).
+fn api_server() -> Result<(Server, String), Error> {
+    let (api_url, where_from) = match std::env::var(config::API_URL_VARIABLE) {
+        Ok(api_url) => (api_url, config::API_URL_VARIABLE),
+        Err(_) => (config::API_URL.to_string(), "API_URL in config.rs")
+    };
+    let api_url = match url::parse(&api_url) {
+        Ok(api_url) => api_url,
+        Err(what_happened) => {
+            return Err(Error::InvalidRequest(format!("{} is invalid: {}", where_from, what_happened)));
+        }
+    };
+    if api_url.scheme != "http" && api_url.scheme != "https" {
+        return Err(Error::InvalidRequest(format!("{} isn't an http url", where_from)));
+    }
+    let server = Server {
+        tls: api_url.is_tls(),
+        domain: api_url.host.to_string(),
+        address: (api_url.host, api_url.port)
+    };
+    Ok((server, api_url.path))
+}
+
+// never waits on a rate limit.  if the route's bucket is used up,
+// this gives back Error::RateLimited with how long until it isn't,
+// so the caller can get on with something else in the meantime.
+fn send(method: &str, path: &[Segment], body: &str) -> Result<Response, Error> {
+    send_with(method, path, &[], &[], body)
+}
+
+// the query's values get percent-encoded, its names are used as is.
+// headers are sent along with the usual ones.
+fn send_with(method: &str, path: &[Segment], query: &[(&str, &str)], extra_headers: &[(&str, &str)], body: &str) -> Result<Response, Error> {
+    let route = route(method, path);
+    let path = match url::build_path(path) {
+        Ok(path) => path,
+        Err(what_happened) => {
+            return Err(Error::InvalidRequest(what_happened));
+        }
+    };
+    if let Some(wait) = reserve(&route) {
+        return Err(Error::RateLimited(wait));
+    }
+
+    let mut headers = vec![("X-Bot-Token", config::BOT_TOKEN)];
+    if !body.is_empty() {
+        headers.push(("Content-Type", "application/json"));
+    }
+    headers.extend_from_slice(extra_headers);
+    let (server, base_path) = api_server()?;
+    let mut full_path = format!("{}{}", base_path, path);
+    if full_path.is_empty() {
+        full_path.push('/');
+    }
+    for (index, (name, value)) in query.iter().enumerate() {
+        full_path.push(if index == 0 { '?' } else { '&' });
+        full_path.push_str(&format!("{}={}", name, url::percent_encode(value)));
+    }
+    let request = http::Request {
+        method,
+        path: &full_path,
+        headers: &headers,
+        body: body.as_bytes()
+    };
+    let result = http::send(&server, &request);
+    match &result {
+        Ok(response) | Err(Error::Redirection(response) | Error::Client(response) | Error::Server(response)) => {
+            update_rate_limits(&route, response);
+        },
+        Err(_) => {}
+    }
+    match result {
+        Err(Error::Client(response)) if response.status == 429 => {
+            let retry_after = if
+                let Ok((Value::Object(response_json), _)) = json::parse_value(&response.body, 0)
                 && let Some(Value::Number(milliseconds)) = response_json.get("retry_after")
                 && let Ok(millis_as_u64) = TryInto::<u64>::try_into(milliseconds.as_int())
             {
@@ -62,36 +352,432 @@ fn send(request: &[u8]) -> Result<(), String> {
                 // we at least know it'll never be higher than 10 secs.
                 Duration::from_secs(10)
             };
-            std::thread::sleep(time_to_sleep);
-            continue;
+            exhaust_bucket(&route, retry_after);
+            Err(Error::RateLimited(retry_after))
+        },
+        result => result
+    }
+}
+
+// for callers that don't have anything better to do than wait:
+// keeps retrying the request until it isn't rate limited.
+pub fn wait_if_rate_limited<T>(mut request: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+    loop {
+        match request() {
+            Err(Error::RateLimited(wait)) => std::thread::sleep(wait),
+            result => {
+                return result;
+            }
         }
-        return Ok(());
     }
 }
 
-pub fn post_message(channel_id: &str, content: &str) -> Result<(), String> {
-    let channel = sanitize(channel_id);
-    let content = sanitize(content);
+// stoat remembers the Idempotency-Key a message was sent with, and refuses
+// another message with the same one (see already_sent), so sending twice can't post twice.
+// that way a message can be sent again when it's not clear whether it went through.
+// this one's for messages that don't have anything to make a key from.
+fn new_idempotency_key() -> String {
+    let random = RandomState::new().build_hasher().finish();
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    format!("{:016x}{:x}", random, nanos)
+}
 
-    let body = format!(r#"{{"content":"{}","embeds":[]}}"#, content);
-    let request = format!("POST /channels/{}/messages HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\nX-Bot-Token: {}\r\n\r\n{}", channel, config::HTTP_ENDPOINT, body.len(), config::BOT_TOKEN, body);
+// whether the api refused a message because one with the same Idempotency-Key was already posted.
+pub fn already_sent(what_happened: &Error) -> bool {
+    refusal(what_happened).is_some_and(|(refusal_type, _)| refusal_type == "DuplicateNonce")
+}
 
-    send(request.as_bytes())
+pub fn post_message(channel_id: &str, content: &str) -> Result<Message, Error> {
+    let body = Value::Object(HashMap::from([
+        ("content".to_string(), Value::String(content.to_string())),
+        ("embeds".to_string(), Value::Array(vec![]))
+    ]));
+    let path = [Segment::Literal("channels"), Segment::Id(channel_id), Segment::Literal("messages")];
+    let idempotency_key = new_idempotency_key();
+    let response = send_with("POST", &path, &[], &[("Idempotency-Key", &idempotency_key)], &json::stringify(&body))?;
+    parse_message(&response)
 }
 
-pub fn post_alarm(alarm: &Alarm) -> Result<(), String> {
-    let message = sanitize(&alarm.what);
-    let channel = sanitize(&alarm.channel_id);
-    let reply_to = sanitize(&alarm.message_id);
+// how an alarm is posted as an embed instead of plain text.
+#[derive(Clone, Debug, Default)]
+pub struct EmbedStyle {
+    // a colour stoat accepts, like "#f5a623" or "orange".  settings.rs checks it.
+    pub colour: Option<String>
+}
 
-    let body = format!(r#"{{"content":"{}","replies":[{{"id":"{}","mention":true,"fail_if_not_exists":false}}]}}"#, message, reply_to);
-    let request = format!("POST /channels/{}/messages HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\nX-Bot-Token: {}\r\n\r\n{}", channel, config::HTTP_ENDPOINT, body.len(), config::BOT_TOKEN, body);
+// posts under a different name and avatar than the bot's own.
+#[derive(Clone, Debug, Default)]
+pub struct Masquerade {
+    pub name: Option<String>,
+    // a url to an image.
+    pub avatar: Option<String>
+}
 
-    send(request.as_bytes())
+fn alarm_embed(alarm: &Alarm, style: &EmbedStyle) -> Value {
+    let mut description = alarm.what.to_string();
+    description.push_str(&format!("\n\nset for {}", alarm.when.format("%Y-%m-%d %H:%M UTC")));
+    if let Some(created) = alarm.created {
+        description.push_str(&format!("\nset at {}", created.format("%Y-%m-%d %H:%M UTC")));
+    }
+    if let Some(author) = &alarm.author {
+        let server_id = cache::channel(&alarm.channel_id).and_then(|channel| channel.server);
+        if let Some(name) = cache::display_name(server_id.as_deref(), author) {
+            description.push_str(&format!("\nset by {}", name));
+        }
+    }
+    let mut embed = HashMap::from([
+        ("title".to_string(), Value::String("⏰ alarm".to_string())),
+        ("description".to_string(), Value::String(description))
+    ]);
+    if let Some(colour) = &style.colour {
+        embed.insert("colour".to_string(), Value::String(colour.to_string()));
+    }
+    Value::Object(embed)
+}
+
+fn masquerade_json(masquerade: &Masquerade) -> Value {
+    let mut object = HashMap::new();
+    if let Some(name) = &masquerade.name {
+        object.insert("name".to_string(), Value::String(name.to_string()));
+    }
+    if let Some(avatar) = &masquerade.avatar {
+        object.insert("avatar".to_string(), Value::String(avatar.to_string()));
+    }
+    Value::Object(object)
+}
+
+// when stoat refuses a request, it says why like {"type":"<why>", ...}.
+// gives back the type and the rest of it.
+fn refusal(what_happened: &Error) -> Option<(String, HashMap<String, Value>)> {
+    let Error::Client(response) = what_happened else {
+        return None;
+    };
+    let Ok((Value::Object(mut refusal), _)) = json::parse_value(&response.body, 0) else {
+        return None;
+    };
+    let Some(Value::String(refusal_type)) = refusal.remove("type") else {
+        return None;
+    };
+    Some((refusal_type, refusal))
+}
+
+// which permission a 403 says the bot is missing, if that's why it was refused.
+// stoat sends back {"type":"MissingPermission","permission":"<name>"}.
+fn missing_permission(what_happened: &Error) -> Option<String> {
+    let (refusal_type, mut refusal) = refusal(what_happened)?;
+    if what_happened.status() != Some(403) || refusal_type != "MissingPermission" {
+        return None;
+    }
+    let Some(Value::String(permission)) = refusal.remove("permission") else {
+        return None;
+    };
+    Some(permission)
+}
+
+// posts the alarm, as a reply to the message that set it.
+// if the channel doesn't let the bot send embeds or masquerade,
+// it tries again without them rather than not posting at all.
+pub fn post_alarm(alarm: &Alarm, mut embed: Option<&EmbedStyle>, mut masquerade: Option<&Masquerade>) -> Result<Message, Error> {
+    loop {
+        let mut body = HashMap::new();
+        match embed {
+            Some(style) => {
+                body.insert("embeds".to_string(), Value::Array(vec![alarm_embed(alarm, style)]));
+            },
+            None => {
+                body.insert("content".to_string(), Value::String(alarm.what.to_string()));
+            }
+        }
+        if let Some(masquerade) = masquerade {
+            body.insert("masquerade".to_string(), masquerade_json(masquerade));
+        }
+        let reply = Value::Object(HashMap::from([
+            ("id".to_string(), Value::String(alarm.message_id.to_string())),
+            ("mention".to_string(), Value::Boolean(true)),
+            ("fail_if_not_exists".to_string(), Value::Boolean(false))
+        ]));
+        body.insert("replies".to_string(), Value::Array(vec![reply]));
+
+        let channel_id = &alarm.channel_id;
+        let path = [Segment::Literal("channels"), Segment::Id(channel_id), Segment::Literal("messages")];
+        // the same every time this alarm's posted this way, so retrying after a failure
+        // that might have gone through can't post it twice.
+        // it's different for each way of posting it, so falling back isn't mistaken for a repeat.
+        let idempotency_key = format!(
+            "alarm-{}{}{}",
+            alarm.message_id,
+            if embed.is_some() { "-embed" } else { "" },
+            if masquerade.is_some() { "-masquerade" } else { "" }
+        );
+        let what_happened = match send_with("POST", &path, &[], &[("Idempotency-Key", &idempotency_key)], &json::stringify(&Value::Object(body))) {
+            Ok(response) => {
+                return parse_message(&response);
+            },
+            Err(what_happened) => what_happened
+        };
+        match missing_permission(&what_happened).as_deref() {
+            Some("SendEmbeds") if embed.is_some() => {
+                println!("stoat api: can't send embeds in channel {}, posting the alarm as plain text", channel_id);
+                embed = None;
+            },
+            Some("Masquerade") if masquerade.is_some() => {
+                println!("stoat api: can't masquerade in channel {}, posting the alarm as the bot", channel_id);
+                masquerade = None;
+            },
+            _ => {
+                return Err(what_happened);
+            }
+        }
+    }
+}
+
+pub fn fetch_message(channel_id: &str, message_id: &str) -> Result<Message, Error> {
+    let path = [Segment::Literal("channels"), Segment::Id(channel_id), Segment::Literal("messages"), Segment::Id(message_id)];
+    let response = send("GET", &path, "")?;
+    parse_message(&response)
+}
+
+// up to limit messages sent in the channel after the given one, oldest first.
+// they're left as json, so they can be handled just like message events.
+pub fn fetch_messages_after(channel_id: &str, after: &str, limit: usize) -> Result<Vec<HashMap<String, Value>>, Error> {
+    if !ulid::is_valid(after) {
+        return Err(Error::InvalidRequest(format!("{:?} is not a valid id", after)));
+    }
+    let path = [Segment::Literal("channels"), Segment::Id(channel_id), Segment::Literal("messages")];
+    let limit = limit.to_string();
+    let query = [("limit", limit.as_str()), ("after", after), ("sort", "Oldest")];
+    let response = send_with("GET", &path, &query, &[], "")?;
+    let Ok((Value::Array(messages), _)) = json::parse_value(&response.body, 0) else {
+        return Err(Error::UnexpectedBody("the messages in the response are not a json array".to_string()));
+    };
+    Ok(messages.into_iter()
+        .filter_map(|message| match message {
+            Value::Object(message) => Some(message),
+            _ => None
+        })
+        .collect())
+}
+
+pub fn edit_message(channel_id: &str, message_id: &str, content: &str) -> Result<Message, Error> {
+    let path = [Segment::Literal("channels"), Segment::Id(channel_id), Segment::Literal("messages"), Segment::Id(message_id)];
+    let body = Value::Object(HashMap::from([
+        ("content".to_string(), Value::String(content.to_string()))
+    ]));
+    let response = send("PATCH", &path, &json::stringify(&body))?;
+    parse_message(&response)
+}
+
+pub fn delete_message(channel_id: &str, message_id: &str) -> Result<(), Error> {
+    let path = [Segment::Literal("channels"), Segment::Id(channel_id), Segment::Literal("messages"), Segment::Id(message_id)];
+    send("DELETE", &path, "")?;
+    Ok(())
+}
+
+pub fn react(channel: &str, message: &str, emoji: &Emoji) -> Result<(), Error> {
+    let path = [
+        Segment::Literal("channels"), Segment::Id(channel),
+        Segment::Literal("messages"), Segment::Id(message),
+        Segment::Literal("reactions"), emoji.segment()
+    ];
+    send("PUT", &path, "")?;
+    Ok(())
+}
+
+// removes the bot's own reaction.
+pub fn unreact(channel: &str, message: &str, emoji: &Emoji) -> Result<(), Error> {
+    let path = [
+        Segment::Literal("channels"), Segment::Id(channel),
+        Segment::Literal("messages"), Segment::Id(message),
+        Segment::Literal("reactions"), emoji.segment()
+    ];
+    send("DELETE", &path, "")?;
+    Ok(())
+}
+
+pub fn fetch_channel(channel_id: &str) -> Result<Channel, Error> {
+    let response = send("GET", &[Segment::Literal("channels"), Segment::Id(channel_id)], "")?;
+    parse_body(&response, Channel::from_json, "channel")
 }
 
-pub fn react(channel: &str, message: &str, emoji: &str) -> Result<(), String> {
-    let request = format!("PUT /channels/{}/messages/{}/reactions/{} HTTP/1.0\r\nHost: {}\r\nX-Bot-Token: {}\r\nContent-Length: 0\r\n\r\n", channel, message, emoji, config::HTTP_ENDPOINT, config::BOT_TOKEN);
+// what the api root says about the server.
+#[derive(Debug)]
+pub struct Root {
+    // the stoat version it's running.
+    pub version: String,
+    // where the event websocket is.
+    pub ws: String,
+    // the optional parts of stoat, and whether each is turned on.
+    pub features: HashMap<String, bool>
+}
+
+impl Root {
+    pub fn from_json(root: &HashMap<String, Value>) -> Option<Self> {
+        let Some(Value::String(ws)) = root.get("ws") else {
+            return None;
+        };
+        let version = match root.get("revolt") {
+            Some(Value::String(version)) => version.to_string(),
+            _ => "unknown".to_string()
+        };
+        // features are either true/false, or an object with "enabled" in it.
+        let mut features = HashMap::new();
+        if let Some(Value::Object(advertised)) = root.get("features") {
+            for (name, feature) in advertised {
+                let enabled = match feature {
+                    Value::Boolean(enabled) => *enabled,
+                    Value::Object(feature) => matches!(feature.get("enabled"), Some(Value::Boolean(true))),
+                    _ => continue
+                };
+                features.insert(name.to_string(), enabled);
+            }
+        }
+        Some(Self {
+            version,
+            ws: ws.to_string(),
+            features
+        })
+    }
+}
 
-    send(request.as_bytes())
+pub fn fetch_root() -> Result<Root, Error> {
+    let response = send("GET", &[], "")?;
+    parse_body(&response, Root::from_json, "api root")
+}
+
+// whoever BOT_TOKEN belongs to.
+pub fn fetch_self() -> Result<User, Error> {
+    let response = send("GET", &[Segment::Literal("users"), Segment::Literal("@me")], "")?;
+    parse_body(&response, User::from_json, "user")
+}
+
+pub fn fetch_user(user_id: &str) -> Result<User, Error> {
+    let response = send("GET", &[Segment::Literal("users"), Segment::Id(user_id)], "")?;
+    parse_body(&response, User::from_json, "user")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&str, &str)]) -> Response {
+        Response {
+            status: 200,
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            body: vec![]
+        }
+    }
+
+    fn object(json_text: &str) -> HashMap<String, Value> {
+        let Ok((Value::Object(object), _)) = json::parse_value(json_text.as_bytes(), 0) else {
+            panic!("not a json object: {}", json_text);
+        };
+        object
+    }
+
+    #[test]
+    fn messages() {
+        let message = Message::from_json(&object(r#"{"_id":"01BX5ZZKBKACTAV9WEVGEMMVRZ","channel":"01ARZ3NDEKTSV4RRFFQ69G5FAV","author":"01BX5ZZKBKACTAV9WEVGEMMVR0","content":"hello","nonce":"ignored"}"#)).unwrap();
+        assert_eq!(message.id, "01BX5ZZKBKACTAV9WEVGEMMVRZ");
+        assert_eq!(message.channel, "01ARZ3NDEKTSV4RRFFQ69G5FAV");
+        assert_eq!(message.author, "01BX5ZZKBKACTAV9WEVGEMMVR0");
+        assert_eq!(message.content, "hello");
+
+        // only embeds, so no content.
+        let message = Message::from_json(&object(r#"{"_id":"a","channel":"b","author":"c","embeds":[]}"#)).unwrap();
+        assert_eq!(message.content, "");
+
+        assert!(Message::from_json(&object(r#"{"channel":"b","author":"c","content":"hi"}"#)).is_none());
+        assert!(Message::from_json(&object(r#"{"_id":"a","author":"c","content":"hi"}"#)).is_none());
+        assert!(Message::from_json(&object(r#"{"_id":"a","channel":"b","content":"hi"}"#)).is_none());
+        assert!(Message::from_json(&object(r#"{"_id":1,"channel":"b","author":"c"}"#)).is_none());
+    }
+
+    #[test]
+    fn unexpected_bodies_have_no_status() {
+        let mut not_a_message = response(&[]);
+        not_a_message.body = br#"{"_id":"a"}"#.to_vec();
+        let what_happened = parse_message(&not_a_message).unwrap_err();
+        assert!(matches!(what_happened, Error::UnexpectedBody(_)), "{:?}", what_happened);
+        assert_eq!(what_happened.status(), None);
+        assert!(!what_happened.is_permanent());
+    }
+
+    #[test]
+    fn routes_leave_out_ids() {
+        let one = route("POST", &[Segment::Literal("channels"), Segment::Id("01ARZ3NDEKTSV4RRFFQ69G5FAV"), Segment::Literal("messages")]);
+        let other = route("POST", &[Segment::Literal("channels"), Segment::Id("01BX5ZZKBKACTAV9WEVGEMMVRZ"), Segment::Literal("messages")]);
+        assert_eq!(one, "POST /channels/{id}/messages");
+        assert_eq!(one, other);
+        assert_eq!(route("PUT", &[Segment::Literal("reactions"), Segment::Text("⏰")]), "PUT /reactions/{text}");
+        assert_eq!(route("GET", &[]), "GET ");
+    }
+
+    // each test uses its own routes and buckets, since the rate limits are shared.
+    #[test]
+    fn reserves_until_the_bucket_is_empty() {
+        assert_eq!(reserve("GET /reserve-test"), None, "unknown routes are never held back");
+        update_rate_limits("GET /reserve-test", &response(&[
+            ("x-ratelimit-bucket", "reserve-test"),
+            ("x-ratelimit-remaining", "2"),
+            ("x-ratelimit-reset-after", "60000")
+        ]));
+        assert_eq!(reserve("GET /reserve-test"), None);
+        assert_eq!(reserve("GET /reserve-test"), None);
+        let wait = reserve("GET /reserve-test").expect("the bucket is empty");
+        assert!(wait > Duration::from_secs(50) && wait <= Duration::from_secs(60), "{:?}", wait);
+
+        // another route in the same bucket is held back too, once it's known to be in it.
+        update_rate_limits("POST /reserve-test", &response(&[
+            ("x-ratelimit-bucket", "reserve-test"),
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset-after", "60000")
+        ]));
+        assert!(reserve("GET /reserve-test").is_some());
+        assert!(reserve("POST /reserve-test").is_some());
+    }
+
+    #[test]
+    fn refills_once_the_reset_has_passed() {
+        update_rate_limits("GET /refill-test", &response(&[
+            ("x-ratelimit-bucket", "refill-test"),
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset-after", "0")
+        ]));
+        assert_eq!(reserve("GET /refill-test"), None);
+    }
+
+    #[test]
+    fn ignores_incomplete_headers() {
+        update_rate_limits("GET /header-test", &response(&[
+            ("x-ratelimit-bucket", "header-test"),
+            ("x-ratelimit-remaining", "0")
+        ]));
+        update_rate_limits("GET /header-test", &response(&[
+            ("X-RateLimit-Bucket", "header-test"),
+            ("X-RateLimit-Remaining", "lots"),
+            ("X-RateLimit-Reset-After", "60000")
+        ]));
+        assert_eq!(reserve("GET /header-test"), None);
+
+        // header names aren't case sensitive.
+        update_rate_limits("GET /header-test", &response(&[
+            ("X-RateLimit-Bucket", "header-test"),
+            ("X-RateLimit-Remaining", "0"),
+            ("X-RateLimit-Reset-After", "60000")
+        ]));
+        assert!(reserve("GET /header-test").is_some());
+    }
+
+    #[test]
+    fn a_429_empties_the_bucket() {
+        exhaust_bucket("GET /exhaust-test", Duration::from_secs(60));
+        assert_eq!(reserve("GET /exhaust-test"), None, "it's not known which bucket the route is in yet");
+        update_rate_limits("GET /exhaust-test", &response(&[
+            ("x-ratelimit-bucket", "exhaust-test"),
+            ("x-ratelimit-remaining", "10"),
+            ("x-ratelimit-reset-after", "60000")
+        ]));
+        exhaust_bucket("GET /exhaust-test", Duration::from_secs(60));
+        assert!(reserve("GET /exhaust-test").is_some());
+    }
 }
@@ -0,0 +1,109 @@
+use std::fmt;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
+
+use crate::alarm::Alarm;
+use crate::alarm_heap::AlarmHeap;
+use crate::config;
+use crate::file;
+use crate::journal;
+
+// picks between the one-file-per-alarm layout and the journal,
+// depending on USE_JOURNAL in config.rs.
+
+// what happened to the saved alarms at startup.
+#[derive(Debug, Default)]
+pub struct LoadSummary {
+    pub loaded: usize,
+    // alarms that were in an older format, and got rewritten.
+    pub migrated: usize,
+    // alarms that couldn't be loaded, and were moved to QUARANTINE_DIR.
+    pub quarantined: usize,
+    // things that were left alone because they aren't alarms.
+    pub skipped: usize
+}
+
+impl fmt::Display for LoadSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "loaded {} alarms ({} migrated), quarantined {}, skipped {}", self.loaded, self.migrated, self.quarantined, self.skipped)
+    }
+}
+
+// held for as long as something is using the saved alarms.
+pub struct Lock {
+    _file: File
+}
+
+// fails if something else, like the running bot, already holds the lock.
+pub fn lock() -> Result<Lock, String> {
+    let file = match OpenOptions::new().create(true).truncate(false).write(true).open(config::LOCK_FILE) {
+        Ok(file) => file,
+        Err(what_happened) => {
+            return Err(format!("failed to open {}: {}", config::LOCK_FILE, what_happened));
+        }
+    };
+    match file.try_lock() {
+        Ok(()) => Ok(Lock {
+            _file: file
+        }),
+        Err(TryLockError::WouldBlock) => {
+            Err(format!("{} is locked, so something else (probably the bot) is using the saved alarms.  stop it and try again", config::LOCK_FILE))
+        },
+        Err(TryLockError::Error(what_happened)) => {
+            Err(format!("failed to lock {}: {}", config::LOCK_FILE, what_happened))
+        }
+    }
+}
+
+// loads the saved alarms for the bot to use.
+// this tidies up as it goes, migrating old formats and quarantining what can't be loaded.
+pub fn load() -> Result<(AlarmHeap, LoadSummary), String> {
+    if config::USE_JOURNAL {
+        journal::load()
+    } else {
+        file::load()
+    }
+}
+
+// like load, but never changes anything on disk, for looking at the alarms from outside the bot.
+// anything that can't be loaded is counted as skipped instead of being quarantined.
+pub fn read() -> Result<(AlarmHeap, LoadSummary), String> {
+    if config::USE_JOURNAL {
+        journal::read()
+    } else {
+        file::read()
+    }
+}
+
+pub fn create(alarm: &Alarm) -> Result<(), String> {
+    if config::USE_JOURNAL {
+        journal::record_create(alarm)
+    } else {
+        file::save(alarm).map_err(|error| error.to_string())
+    }
+}
+
+pub fn update(alarm: &Alarm) -> Result<(), String> {
+    if config::USE_JOURNAL {
+        journal::record_update(alarm)
+    } else {
+        file::save(alarm).map_err(|error| error.to_string())
+    }
+}
+
+pub fn cancel(alarm: &Alarm) -> Result<(), String> {
+    if config::USE_JOURNAL {
+        journal::record_cancel(alarm)
+    } else {
+        file::delete(alarm).map_err(|error| error.to_string())
+    }
+}
+
+pub fn fire(alarm: &Alarm) -> Result<(), String> {
+    if config::USE_JOURNAL {
+        journal::record_fire(alarm)
+    } else {
+        file::delete(alarm).map_err(|error| error.to_string())
+    }
+}
@@ -0,0 +1,141 @@
+use std::sync::Mutex;
+
+use chrono::FixedOffset;
+use chrono::NaiveDateTime;
+use chrono::TimeZone;
+use chrono::Utc;
+
+use crate::config;
+use crate::json::IntOrFloat;
+use crate::json::Value;
+use crate::saved_map::SavedMap;
+
+// each user's timezone, as set with the "timezone" command.
+// it's kept in TIMEZONES_FILE as one json object,
+// with how far ahead of utc each user is in minutes:
+// {"<user id>":120, ...}
+// users who haven't set one get utc.
+
+// nowhere is further than this from utc.
+const MAX_OFFSET_MINUTES: i64 = 14 * 60;
+
+fn minutes(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(IntOrFloat::Int(minutes)) if minutes.abs() <= MAX_OFFSET_MINUTES => Some(*minutes),
+        _ => None
+    }
+}
+
+fn minutes_to_json(minutes: &i64) -> Value {
+    Value::Number(IntOrFloat::Int(*minutes))
+}
+
+static TIMEZONES: Mutex<SavedMap<i64>> = Mutex::new(SavedMap::new(config::TIMEZONES_FILE, minutes, minutes_to_json));
+
+fn from_minutes(minutes: i64) -> FixedOffset {
+    FixedOffset::east_opt(minutes as i32 * 60).unwrap_or(FixedOffset::east_opt(0).expect("utc is a valid offset"))
+}
+
+// the user's timezone, or utc if they haven't set one.
+pub fn of(user_id: &str) -> FixedOffset {
+    let Ok(mut timezones) = TIMEZONES.lock() else {
+        return from_minutes(0);
+    };
+    let minutes = timezones.entries().get(user_id).copied().unwrap_or(0);
+    from_minutes(minutes)
+}
+
+pub fn set(user_id: &str, offset: FixedOffset) -> Result<(), String> {
+    let Ok(mut timezones) = TIMEZONES.lock() else {
+        return Err("timezones mutex has been poisoned".to_string());
+    };
+    timezones.entries().insert(user_id.to_string(), offset.local_minus_utc() as i64 / 60);
+    timezones.save()
+}
+
+// reads offsets like "utc", "utc+2", "+02:00", "-0530" or "gmt-8".
+pub fn parse(text: &str) -> Option<FixedOffset> {
+    let text = text.trim().to_lowercase();
+    let (named, offset) = match text.strip_prefix("utc").or_else(|| text.strip_prefix("gmt")) {
+        Some(offset) => (true, offset),
+        None => (false, text.as_str())
+    };
+    if offset.is_empty() {
+        return named.then(|| from_minutes(0));
+    }
+    let (sign, offset) = if let Some(offset) = offset.strip_prefix('+') {
+        (1, offset)
+    } else if let Some(offset) = offset.strip_prefix('-') {
+        (-1, offset)
+    } else {
+        return None;
+    };
+    if offset.is_empty() || !offset.chars().all(|character| character.is_ascii_digit() || character == ':') {
+        return None;
+    }
+    let (hours, minutes) = match offset.split_once(':') {
+        Some((hours, minutes)) if minutes.len() == 2 => (hours, minutes),
+        Some(_) => return None,
+        // "0530" is five and a half hours, "5" is just five.
+        None if offset.len() > 2 => offset.split_at(offset.len() - 2),
+        None => (offset, "0")
+    };
+    // no offset has more than two digits of hours,
+    // and a longer number could overflow working out the minutes.
+    if hours.len() > 2 {
+        return None;
+    }
+    let hours = hours.parse::<i64>().ok()?;
+    let minutes = minutes.parse::<i64>().ok()?;
+    if minutes >= 60 {
+        return None;
+    }
+    let total = sign * (hours * 60 + minutes);
+    (total.abs() <= MAX_OFFSET_MINUTES).then(|| from_minutes(total))
+}
+
+// like "utc" or "utc+02:00".
+pub fn name(offset: FixedOffset) -> String {
+    if offset.local_minus_utc() == 0 {
+        "utc".to_string()
+    } else {
+        format!("utc{}", offset)
+    }
+}
+
+// a utc time, shown in the given timezone.
+pub fn format(when: &NaiveDateTime, offset: FixedOffset) -> String {
+    format!("{} {}", Utc.from_utc_datetime(when).with_timezone(&offset).format("%Y-%m-%d %H:%M"), name(offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets() {
+        let minutes = |text: &str| parse(text).map(|offset| offset.local_minus_utc() / 60);
+        assert_eq!(minutes("utc"), Some(0));
+        assert_eq!(minutes("GMT"), Some(0));
+        assert_eq!(minutes("utc+2"), Some(120));
+        assert_eq!(minutes("+02:00"), Some(120));
+        assert_eq!(minutes("-0530"), Some(-330));
+        assert_eq!(minutes("UTC-8"), Some(-480));
+        assert_eq!(minutes("+14"), Some(14 * 60));
+        assert_eq!(minutes("+15"), None);
+        assert_eq!(minutes("2"), None);
+        assert_eq!(minutes("+2:5"), None);
+        assert_eq!(minutes("+02:60"), None);
+        assert_eq!(minutes("europe/london"), None);
+        assert_eq!(minutes("utc+"), None);
+        assert_eq!(minutes("+999999999999999999:00"), None);
+        assert_eq!(minutes("-99999999999999999999"), None);
+    }
+
+    #[test]
+    fn formatting() {
+        let when = NaiveDateTime::parse_from_str("2026-01-01 23:30", "%Y-%m-%d %H:%M").unwrap();
+        assert_eq!(format(&when, from_minutes(0)), "2026-01-01 23:30 utc");
+        assert_eq!(format(&when, from_minutes(90)), "2026-01-02 01:00 utc+01:30");
+    }
+}
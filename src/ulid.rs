@@ -0,0 +1,63 @@
+// stoat ids are ulids: 26 characters of crockford's base32,
+// the first 10 being a millisecond timestamp and the last 16 being random.
+// see <https://github.com/ulid/spec>
+
+use chrono::DateTime;
+use chrono::NaiveDateTime;
+
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const LENGTH: usize = 26;
+const TIMESTAMP_LENGTH: usize = 10;
+
+pub fn is_valid(id: &str) -> bool {
+    let bytes = id.as_bytes();
+    // 26 base32 characters hold 130 bits, but a ulid is only 128,
+    // so the first character can't be any higher than 7.
+    bytes.len() == LENGTH
+        && bytes[0] <= b'7'
+        && bytes.iter().all(|byte| ALPHABET.contains(byte))
+}
+
+// when the id was made, which for a stoat message is when it was sent.
+pub fn timestamp(id: &str) -> Option<NaiveDateTime> {
+    if !is_valid(id) {
+        return None;
+    }
+    let mut millis = 0i64;
+    for byte in &id.as_bytes()[..TIMESTAMP_LENGTH] {
+        let digit = ALPHABET.iter().position(|letter| letter == byte)?;
+        millis = millis * 32 + digit as i64;
+    }
+    Some(DateTime::from_timestamp_millis(millis)?.naive_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid() {
+        assert!(is_valid("01ARZ3NDEKTSV4RRFFQ69G5FAV"));
+        assert!(is_valid("7ZZZZZZZZZZZZZZZZZZZZZZZZZ"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(!is_valid(""));
+        assert!(!is_valid("01ARZ3NDEKTSV4RRFFQ69G5FA"));
+        assert!(!is_valid("01ARZ3NDEKTSV4RRFFQ69G5FAVV"));
+        assert!(!is_valid("8ZZZZZZZZZZZZZZZZZZZZZZZZZ"));
+        assert!(!is_valid("01arz3ndektsv4rrffq69g5fav"));
+        assert!(!is_valid("01ARZ3NDEKTSV4RRFFQ69G5FAU"));
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp("00000000000000000000000000").unwrap().and_utc().timestamp_millis(), 0);
+        assert_eq!(timestamp("01ARZ3NDEKTSV4RRFFQ69G5FAV").unwrap().and_utc().timestamp_millis(), 1469922850259);
+        // the biggest timestamp a ulid can hold.
+        assert_eq!(timestamp("7ZZZZZZZZZZZZZZZZZZZZZZZZZ").unwrap().and_utc().timestamp_millis(), (1 << 48) - 1);
+        assert_eq!(timestamp("01arz3ndektsv4rrffq69g5fav"), None);
+        assert_eq!(timestamp(""), None);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::ulid;
+
+// builds request paths for the stoat api.
+// ids are checked before they go in, so a bad one can't change which
+// endpoint gets hit, and anything else is percent-encoded as utf8.
+//
+// also splits up the urls of the servers the bot talks to,
+// for when they're set at runtime instead of in config.rs.
+
+pub enum Segment<'a> {
+    // part of the endpoint itself, like "channels".  used as is.
+    Literal(&'a str),
+    // a stoat id.  must be a valid ulid.
+    Id(&'a str),
+    // anything else, like a unicode emoji.  gets percent-encoded.
+    Text(&'a str)
+}
+
+// percent-encodes everything except the characters rfc 3986 calls unreserved.
+pub fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char);
+            },
+            _ => {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    encoded
+}
+
+pub fn build_path(segments: &[Segment]) -> Result<String, String> {
+    let mut path = String::new();
+    for segment in segments {
+        path.push('/');
+        match segment {
+            Segment::Literal(literal) => path.push_str(literal),
+            Segment::Id(id) => {
+                if !ulid::is_valid(id) {
+                    return Err(format!("{:?} is not a valid id", id));
+                }
+                path.push_str(id);
+            },
+            Segment::Text(text) => {
+                if text.is_empty() {
+                    return Err("path segment is empty".to_string());
+                }
+                path.push_str(&percent_encode(text));
+            }
+        }
+    }
+    Ok(path)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Url {
+    // "http", "https", "ws" or "wss".
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    // everything after the host, without a trailing slash.  empty for the root.
+    pub path: String
+}
+
+impl Url {
+    pub fn is_tls(&self) -> bool {
+        self.scheme == "https" || self.scheme == "wss"
+    }
+}
+
+// only handles what the bot needs: <scheme>://<host>[:<port>][/<path>]
+// with no user info, query or fragment.  ipv6 hosts go in brackets.
+pub fn parse(text: &str) -> Result<Url, String> {
+    let Some((scheme, rest)) = text.trim().split_once("://") else {
+        return Err(format!("{:?} has no scheme", text));
+    };
+    let scheme = scheme.to_ascii_lowercase();
+    let default_port = match scheme.as_str() {
+        "http" | "ws" => 80,
+        "https" | "wss" => 443,
+        _ => {
+            return Err(format!("{:?} isn't an http or websocket url", text));
+        }
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, "")
+    };
+    if authority.contains(['@', '?', '#']) || path.contains(['?', '#']) {
+        return Err(format!("{:?} has parts the bot doesn't understand", text));
+    }
+    let (host, port) = match authority.rsplit_once(':') {
+        // the colons inside an ipv6 address aren't a port.
+        Some((host, port)) if !port.contains(']') => {
+            let Ok(port) = port.parse::<u16>() else {
+                return Err(format!("{:?} has an invalid port", text));
+            };
+            (host, port)
+        },
+        _ => (authority, default_port)
+    };
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return Err(format!("{:?} has no host", text));
+    }
+    Ok(Url {
+        scheme,
+        host: host.to_string(),
+        port,
+        path: path.trim_end_matches('/').to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emoji() {
+        assert_eq!(percent_encode("⏰"), "%E2%8F%B0");
+        assert_eq!(percent_encode("✅"), "%E2%9C%85");
+        assert_eq!(percent_encode("👍🏽"), "%F0%9F%91%8D%F0%9F%8F%BD");
+        assert_eq!(percent_encode("a b/c?d#e%"), "a%20b%2Fc%3Fd%23e%25");
+    }
+
+    #[test]
+    fn path() {
+        let path = build_path(&[
+            Segment::Literal("channels"), Segment::Id("01ARZ3NDEKTSV4RRFFQ69G5FAV"),
+            Segment::Literal("reactions"), Segment::Text("⏰")
+        ]);
+        assert_eq!(path, Ok("/channels/01ARZ3NDEKTSV4RRFFQ69G5FAV/reactions/%E2%8F%B0".to_string()));
+    }
+
+    #[test]
+    fn bad_ids() {
+        assert!(build_path(&[Segment::Literal("channels"), Segment::Id("../users/@me")]).is_err());
+        assert!(build_path(&[Segment::Literal("channels"), Segment::Id("")]).is_err());
+        assert!(build_path(&[Segment::Literal("channels"), Segment::Text("")]).is_err());
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(parse("https://api.stoat.chat"), Ok(Url {
+            scheme: "https".to_string(),
+            host: "api.stoat.chat".to_string(),
+            port: 443,
+            path: String::new()
+        }));
+        assert_eq!(parse("ws://127.0.0.1:8001/"), Ok(Url {
+            scheme: "ws".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 8001,
+            path: String::new()
+        }));
+        assert_eq!(parse("HTTP://[::1]:8000/api/"), Ok(Url {
+            scheme: "http".to_string(),
+            host: "::1".to_string(),
+            port: 8000,
+            path: "/api".to_string()
+        }));
+        assert!(!parse("http://localhost").unwrap().is_tls());
+        assert!(parse("wss://events.stoat.chat/").unwrap().is_tls());
+    }
+
+    #[test]
+    fn bad_urls() {
+        assert!(parse("api.stoat.chat").is_err());
+        assert!(parse("ftp://api.stoat.chat").is_err());
+        assert!(parse("https://:443").is_err());
+        assert!(parse("https://api.stoat.chat:port").is_err());
+        assert!(parse("https://user@api.stoat.chat").is_err());
+        assert!(parse("https://api.stoat.chat/?x=1").is_err());
+    }
+}
@@ -0,0 +1,395 @@
+// runs the real bot against a mock stoat server (see mock_stoat),
+// and checks what it does over the network.
+
+mod mock_stoat;
+
+use mock_stoat::AUTHOR_ID;
+use mock_stoat::BOT_ID;
+use mock_stoat::Bot;
+use mock_stoat::CHANNEL_ID;
+use mock_stoat::DM_CHANNEL_ID;
+use mock_stoat::Call;
+use mock_stoat::MockStoat;
+use mock_stoat::OTHER_BOT_ID;
+use mock_stoat::SERVER_ID;
+
+fn is_post(call: &Call) -> bool {
+    call.method == "POST" && call.path == format!("/channels/{}/messages", CHANNEL_ID)
+}
+
+#[test]
+fn sets_and_delivers_an_alarm() {
+    let mock = MockStoat::start();
+    let bot = Bot::start(&mock, "sets_and_delivers_an_alarm");
+    let message_id = mock.send_mention(&format!("<@{}> in 1s wake up", BOT_ID));
+
+    // it says it's got it with a ✅,
+    mock.wait_for_call(|call| call.method == "PUT" && call.path == format!("/channels/{}/messages/{}/reactions/%E2%9C%85", CHANNEL_ID, message_id));
+    let authenticate = &mock.received()[0];
+    assert_eq!(mock_stoat::string_field(authenticate, "type").as_deref(), Some("Authenticate"));
+    assert!(mock_stoat::exists(&bot.path(&format!("alarms/{}/{}", CHANNEL_ID, message_id))));
+
+    // then reacts with ⏰ and replies to the message when it goes off.
+    mock.wait_for_call(|call| call.method == "PUT" && call.path.ends_with("/reactions/%E2%8F%B0"));
+    let posted = mock.wait_for_call(is_post);
+    assert_eq!(mock_stoat::string_field(&posted.body, "content").as_deref(), Some("wake up"));
+    assert_eq!(mock_stoat::string_field(&posted.body, "id").as_deref(), Some(message_id.as_str()));
+    assert!(mock_stoat::wait_until(|| (!mock_stoat::exists(&bot.path(&format!("alarms/{}/{}", CHANNEL_ID, message_id)))).then_some(())).is_some());
+    let history = std::fs::read_to_string(bot.path("history.jsonl")).unwrap();
+    assert!(history.contains(AUTHOR_ID), "{}", bot.log());
+}
+
+#[test]
+fn posts_the_license() {
+    let mock = MockStoat::start();
+    let bot = Bot::start(&mock, "posts_the_license");
+    mock.send_mention("license");
+    let posted = mock.wait_for_call(is_post);
+    assert!(mock_stoat::string_field(&posted.body, "content").unwrap().contains("affero"));
+    // the websocket was found through the api root, which said january's off.
+    assert!(bot.log().contains("warning: january is turned off"), "{}", bot.log());
+}
+
+#[test]
+fn lists_and_cancels_alarms() {
+    let mock = MockStoat::start();
+    let bot = Bot::start(&mock, "lists_and_cancels_alarms");
+    let message_id = mock.send_mention("in 1h put the kettle on");
+    mock.wait_for_call(|call| call.method == "PUT" && call.path.contains(&message_id));
+
+    mock.send_mention("tz +1");
+    mock.send_mention("List");
+    let posts = mock.wait_for_calls(2, is_post);
+    assert_eq!(mock_stoat::string_field(&posts[0].body, "content").as_deref(), Some("your timezone is now utc+01:00."));
+    let listed = mock_stoat::string_field(&posts[1].body, "content").unwrap();
+    assert!(listed.contains("1. put the kettle on (at ") && listed.contains("utc+01:00"), "{}", listed);
+
+    mock.send_mention("cancel 1");
+    let posts = mock.wait_for_calls(3, is_post);
+    assert_eq!(mock_stoat::string_field(&posts[2].body, "content").as_deref(), Some("cancelled \"put the kettle on\"."));
+    assert!(!mock_stoat::exists(&bot.path(&format!("alarms/{}/{}", CHANNEL_ID, message_id))), "{}", bot.log());
+}
+
+#[test]
+fn exports_only_while_the_bot_is_stopped() {
+    let mock = MockStoat::start();
+    let mut bot = Bot::start(&mock, "exports_only_while_the_bot_is_stopped");
+    let message_id = mock.send_mention("in 1h back this up");
+    mock.wait_for_call(|call| call.method == "PUT" && call.path.contains(&message_id));
+
+    let refused = bot.run(&["export", "backup.json"]);
+    assert_eq!(refused.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("alarms.lock is locked"), "{:?}", refused);
+    assert!(!mock_stoat::exists(&bot.path("backup.json")));
+
+    bot.stop();
+    let exported = bot.run(&["export", "backup.json"]);
+    assert!(exported.status.success(), "{:?}", exported);
+    let backup = std::fs::read_to_string(bot.path("backup.json")).unwrap();
+    assert!(backup.contains(&message_id) && backup.contains("back this up"), "{}", backup);
+
+    // importing what's already saved changes nothing.
+    let imported = bot.run(&["import", "backup.json", "--replace"]);
+    assert_eq!(String::from_utf8_lossy(&imported.stdout).trim(), "imported 1 alarms, skipped 0 duplicates, removed 0");
+    assert!(mock_stoat::exists(&bot.path(&format!("alarms/{}/{}", CHANNEL_ID, message_id))));
+}
+
+#[test]
+fn explains_commands_and_ignores_chatter() {
+    let mock = MockStoat::start();
+    let _bot = Bot::start(&mock, "explains_commands_and_ignores_chatter");
+    mock.send_mention("in soon do something");
+    let posted = mock.wait_for_call(is_post);
+    let reply = mock_stoat::string_field(&posted.body, "content").unwrap();
+    assert!(reply.contains("usage: `@bot in <time> <message>`"), "{}", reply);
+    // anything that isn't a command is left alone,
+    mock.send_mention("thanks for the help");
+    mock.send_mention("back in a bit");
+    // unless there's a time after "in " somewhere, which is how alarms used to be set.
+    let message_id = mock.send_mention("remind me in 1h to stretch");
+    mock.wait_for_call(|call| call.method == "PUT" && call.path.contains(&message_id));
+    assert_eq!(mock.calls().iter().filter(|call| is_post(call)).count(), 1);
+}
+
+#[test]
+fn ignores_bots_system_messages_and_itself() {
+    let mock = MockStoat::start();
+    // a bot the ready event didn't mention, so it's looked up.
+    const UNKNOWN_BOT_ID: &str = "01J00000000000000000NKN0WN";
+    mock.respond_once("GET", &format!("/users/{}", UNKNOWN_BOT_ID), 200, &format!(r#"{{"_id":"{}","username":"stranger","bot":{{"owner":"{}"}}}}"#, UNKNOWN_BOT_ID, AUTHOR_ID));
+    let bot = Bot::start(&mock, "ignores_bots_system_messages_and_itself");
+
+    mock.send_mention_from(OTHER_BOT_ID, "", "in 1s from a bot");
+    mock.send_mention_from(UNKNOWN_BOT_ID, "", "in 1s from a bot it had to look up");
+    mock.send_mention_from(BOT_ID, "", "in 1s from itself");
+    mock.send_mention_from(AUTHOR_ID, r#""masquerade":{"name":"bridged"}"#, "in 1s from a bridge");
+    mock.send_mention_from("00000000000000000000000000", r#""system":{"type":"user_joined","id":"01J0000000000000000000ATHR"}"#, "in 1s from the system");
+    // messages are handled in order (apart from looking up the unknown bot, which is quick),
+    // so once this is answered the rest have been too.
+    mock.send_mention("license");
+
+    mock.wait_for_call(is_post);
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert_eq!(mock.calls().iter().filter(|call| is_post(call)).count(), 1, "{}", bot.log());
+    assert!(!mock.calls().iter().any(|call| call.method == "PUT"), "{}", bot.log());
+    // the bot in the ready event didn't need looking up, but the other one did.
+    assert!(mock.calls().iter().any(|call| call.path == format!("/users/{}", UNKNOWN_BOT_ID)));
+    assert!(!mock.calls().iter().any(|call| call.path == format!("/users/{}", OTHER_BOT_ID)));
+    assert!(bot.log().contains("a bot sent it"), "{}", bot.log());
+    assert!(bot.log().contains("the bot sent it"), "{}", bot.log());
+    assert!(bot.log().contains("it's masqueraded"), "{}", bot.log());
+    assert!(bot.log().contains("it's a system message"), "{}", bot.log());
+}
+
+#[test]
+fn ignores_authors_it_cant_look_up() {
+    let mock = MockStoat::start();
+    const STRANGER_ID: &str = "01J0000000000000000STRANGE";
+    mock.respond_once("GET", &format!("/users/{}", STRANGER_ID), 500, "");
+    let bot = Bot::start(&mock, "ignores_authors_it_cant_look_up");
+
+    mock.send_mention_from(STRANGER_ID, "", "in 1s maybe from a bot");
+    assert!(mock_stoat::wait_until(|| bot.log().contains("assuming they are").then_some(())).is_some(), "{}", bot.log());
+    // the failure's remembered, so it isn't asked about again straight away.
+    mock.send_mention_from(STRANGER_ID, "", "in 1s still maybe from a bot");
+    mock.send_mention("license");
+
+    mock.wait_for_call(is_post);
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert_eq!(mock.calls().iter().filter(|call| is_post(call)).count(), 1, "{}", bot.log());
+    assert!(!mock.calls().iter().any(|call| call.method == "PUT"), "{}", bot.log());
+    assert_eq!(mock.calls().iter().filter(|call| call.path == format!("/users/{}", STRANGER_ID)).count(), 1, "{}", bot.log());
+}
+
+#[test]
+fn looks_up_an_author_once() {
+    let mock = MockStoat::start();
+    const STRANGER_ID: &str = "01J0000000000000000STRANGE";
+    let bot = Bot::start(&mock, "looks_up_an_author_once");
+
+    // the second waits on the first's lookup, or finds it already done.
+    mock.send_mention_from(STRANGER_ID, "", "license");
+    mock.send_mention_from(STRANGER_ID, "", "license");
+    mock.wait_for_calls(2, is_post);
+    assert_eq!(mock.calls().iter().filter(|call| call.path == format!("/users/{}", STRANGER_ID)).count(), 1, "{}", bot.log());
+}
+
+#[test]
+fn waits_out_a_rate_limit() {
+    let mock = MockStoat::start();
+    mock.respond_once("POST", &format!("/channels/{}/messages", CHANNEL_ID), 429, r#"{"retry_after":500}"#);
+    let _bot = Bot::start(&mock, "waits_out_a_rate_limit");
+    mock.send_mention("in 1s try again");
+
+    let posts = mock.wait_for_calls(2, is_post);
+    assert_eq!(mock_stoat::string_field(&posts[1].body, "content").as_deref(), Some("try again"));
+    // the ⏰ reaction went through the first time, so it isn't repeated.
+    let fired_reactions = mock.calls().iter().filter(|call| call.path.ends_with("/reactions/%E2%8F%B0")).count();
+    assert_eq!(fired_reactions, 1);
+}
+
+#[test]
+fn falls_back_to_plain_text_without_embed_permission() {
+    let mock = MockStoat::start();
+    mock.respond_once("POST", &format!("/channels/{}/messages", CHANNEL_ID), 403, r#"{"type":"MissingPermission","permission":"SendEmbeds"}"#);
+    let dir = Bot::temp_dir("falls_back_to_plain_text_without_embed_permission");
+    std::fs::create_dir_all(dir.join("settings")).unwrap();
+    std::fs::write(dir.join(format!("settings/{}.json", SERVER_ID)), r#"{"embed": true, "embed_colour": "orange"}"#).unwrap();
+    let _bot = Bot::start_in(&mock, dir);
+    mock.send_mention("in 1s stretch");
+
+    let posts = mock.wait_for_calls(2, is_post);
+    assert!(posts[0].body.contains(r#""embeds":["#));
+    assert_eq!(mock_stoat::string_field(&posts[0].body, "colour").as_deref(), Some("orange"));
+    // the author's name comes from the ready event.
+    assert!(mock_stoat::string_field(&posts[0].body, "description").unwrap().ends_with("\nset by someone"), "{}", posts[0].body);
+    assert!(!posts[1].body.contains("embeds"));
+    assert_eq!(mock_stoat::string_field(&posts[1].body, "content").as_deref(), Some("stretch"));
+    // the channel's server came from the ready event, so it wasn't looked up.
+    assert!(!mock.calls().iter().any(|call| call.method == "GET" && call.path == format!("/channels/{}", CHANNEL_ID)));
+}
+
+#[test]
+fn posts_once_when_the_connection_breaks_after_posting() {
+    let mock = MockStoat::start();
+    mock.hang_up_once("POST", &format!("/channels/{}/messages", CHANNEL_ID));
+    let bot = Bot::start(&mock, "posts_once_when_the_connection_breaks_after_posting");
+    let message_id = mock.send_mention("in 1s just the once");
+
+    // it's sent again, but stoat knows it's a repeat.
+    let posts = mock.wait_for_calls(2, is_post);
+    assert_eq!(posts[0].idempotency_key, posts[1].idempotency_key);
+    assert!(posts[0].idempotency_key.is_some());
+    assert!(mock_stoat::wait_until(|| (!mock_stoat::exists(&bot.path(&format!("alarms/{}/{}", CHANNEL_ID, message_id)))).then_some(())).is_some(), "{}", bot.log());
+    assert!(bot.log().contains("was already posted"), "{}", bot.log());
+    assert!(!mock_stoat::exists(&bot.path(&format!("dead-letter/{}.json", message_id))));
+}
+
+#[test]
+fn dead_letters_an_alarm_it_cant_post() {
+    let mock = MockStoat::start();
+    mock.respond_once("POST", &format!("/channels/{}/messages", CHANNEL_ID), 404, r#"{"type":"NotFound"}"#);
+    let bot = Bot::start(&mock, "dead_letters_an_alarm_it_cant_post");
+    let message_id = mock.send_mention("in 1s into the void");
+
+    let dead_letter = bot.path(&format!("dead-letter/{}.json", message_id));
+    assert!(mock_stoat::wait_until(|| mock_stoat::exists(&dead_letter).then_some(())).is_some(), "{}", bot.log());
+    // it's a permanent failure, so there's no point retrying.
+    assert_eq!(mock.calls().iter().filter(|call| is_post(call)).count(), 1);
+    // and whoever set it is told, in the dm the bot already has open with them.
+    let told = mock.wait_for_call(|call| call.method == "POST" && call.path == format!("/channels/{}/messages", DM_CHANNEL_ID));
+    assert!(told.body.contains("into the void") && told.body.contains("test server"), "{}", told.body);
+}
+
+#[test]
+fn keeps_alarms_when_the_token_stops_working() {
+    let mock = MockStoat::start();
+    let mut bot = Bot::start(&mock, "keeps_alarms_when_the_token_stops_working");
+    let message_id = mock.send_mention("in 1s don't lose me");
+    mock.wait_for_call(|call| call.method == "PUT" && call.path.contains(&message_id));
+    mock.respond_once("PUT", &format!("/channels/{}/messages/{}/reactions/%E2%8F%B0", CHANNEL_ID, message_id), 401, r#"{"type":"InvalidSession"}"#);
+
+    assert!(mock_stoat::wait_until(|| bot.exit_status()).is_some(), "{}", bot.log());
+    assert!(bot.log().contains("make sure it's set correctly"), "{}", bot.log());
+    assert!(mock_stoat::exists(&bot.path(&format!("alarms/{}/{}", CHANNEL_ID, message_id))), "{}", bot.log());
+    assert!(!mock_stoat::exists(&bot.path(&format!("dead-letter/{}.json", message_id))));
+}
+
+#[test]
+fn reconnects_after_the_websocket_drops() {
+    let mock = MockStoat::start();
+    let bot = Bot::start(&mock, "reconnects_after_the_websocket_drops");
+    mock_stoat::wait_until(|| (count_frames(&mock, "Authenticate") == 1).then_some(()));
+    // the first heartbeat's round trip is logged.
+    assert!(mock_stoat::wait_until(|| bot.log().contains("heartbeat answered in").then_some(())).is_some(), "{}", bot.log());
+
+    mock.disconnect();
+    let reconnected = mock_stoat::wait_until(|| (count_frames(&mock, "Authenticate") == 2).then_some(()));
+    assert!(reconnected.is_some(), "{}", bot.log());
+
+    // and it still hears about new messages afterwards.
+    mock.send_mention("license");
+    mock.wait_for_call(is_post);
+    assert!(bot.log().contains("event listener: disconnected"), "{}", bot.log());
+    assert!(bot.log().contains("(the last heartbeat was answered in "), "{}", bot.log());
+}
+
+fn count_frames(mock: &MockStoat, frame_type: &str) -> usize {
+    mock.received().iter().filter(|frame| mock_stoat::string_field(frame, "type").as_deref() == Some(frame_type)).count()
+}
+
+#[test]
+fn reconnects_when_heartbeats_go_unanswered() {
+    let mock = MockStoat::start();
+    mock.ignore_pings();
+    let bot = Bot::start(&mock, "reconnects_when_heartbeats_go_unanswered");
+
+    // the first heartbeat goes out as soon as it's connected.
+    assert!(mock_stoat::wait_until(|| (count_frames(&mock, "Ping") >= 1).then_some(())).is_some(), "{}", bot.log());
+    // and when it's still unanswered at the next one, it gives up on the connection.
+    let reconnected = mock_stoat::wait_until(|| (count_frames(&mock, "Authenticate") >= 2).then_some(()));
+    assert!(reconnected.is_some(), "{}", bot.log());
+    assert!(bot.log().contains("heartbeat went unanswered"), "{}", bot.log());
+}
+
+#[test]
+fn catches_up_on_missed_mentions() {
+    let mock = MockStoat::start();
+    let dir = Bot::temp_dir("catches_up_on_missed_mentions");
+    // the bot last saw something in the channel before these were sent.
+    let last_seen = mock_stoat::new_id();
+    std::fs::write(dir.join("last-seen.json"), format!(r#"{{"{}":"{}"}}"#, CHANNEL_ID, last_seen)).unwrap();
+    let missed = mock.miss_mention("in 1s you missed this");
+    // this one's caught up on and heard about live, but should only be set once.
+    let both = mock.send_mention("in 1h don't set me twice");
+    let bot = Bot::start_in(&mock, dir);
+
+    let posted = mock.wait_for_call(is_post);
+    assert_eq!(mock_stoat::string_field(&posted.body, "content").as_deref(), Some("you missed this"));
+    assert_eq!(mock_stoat::string_field(&posted.body, "id"), Some(missed));
+    let is_confirmation = |call: &Call| call.method == "PUT" && call.path.contains(&both) && call.path.ends_with("/reactions/%E2%9C%85");
+    mock.wait_for_call(is_confirmation);
+    // give the live event time to come through too.
+    assert!(mock_stoat::wait_until(|| mock.received().iter().any(|frame| frame.contains(r#""type":"Ping""#)).then_some(())).is_some());
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert_eq!(mock.calls().iter().filter(|call| is_confirmation(call)).count(), 1, "{}", bot.log());
+}
+
+#[test]
+fn doesnt_redo_commands_after_a_restart() {
+    let mock = MockStoat::start();
+    let dir = Bot::temp_dir("doesnt_redo_commands_after_a_restart");
+    std::fs::write(dir.join("last-seen.json"), format!(r#"{{"{}":"{}"}}"#, CHANNEL_ID, mock_stoat::new_id())).unwrap();
+    let mut first = Bot::start_in(&mock, dir.clone());
+    let message_id = mock.send_mention("in 1h only once");
+    let is_confirmation = |call: &Call| call.method == "PUT" && call.path.contains(&message_id) && call.path.ends_with("/reactions/%E2%9C%85");
+    mock.wait_for_call(is_confirmation);
+    // killed well before last seen would have been written on its own.
+    first.stop();
+
+    let second = Bot::start_in(&mock, dir);
+    mock.send_mention("list");
+    let listed = mock.wait_for_call(is_post);
+    let listed = mock_stoat::string_field(&listed.body, "content").unwrap();
+    assert!(listed.starts_with("your alarms (1):"), "{}\n{}", listed, second.log());
+    assert_eq!(mock.calls().iter().filter(|call| is_confirmation(call)).count(), 1, "{}", second.log());
+}
+
+#[test]
+fn answers_each_command_once_when_catching_up() {
+    let mock = MockStoat::start();
+    let dir = Bot::temp_dir("answers_each_command_once_when_catching_up");
+    std::fs::write(dir.join("last-seen.json"), format!(r#"{{"{}":"{}"}}"#, CHANNEL_ID, mock_stoat::new_id())).unwrap();
+    // caught up on, and heard about live once the bot's listening.
+    mock.send_mention("license");
+    let bot = Bot::start_in(&mock, dir);
+
+    mock.wait_for_call(is_post);
+    assert!(mock_stoat::wait_until(|| mock.received().iter().any(|frame| frame.contains(r#""type":"Ping""#)).then_some(())).is_some());
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    assert_eq!(mock.calls().iter().filter(|call| is_post(call)).count(), 1, "{}", bot.log());
+}
+
+#[test]
+fn gives_up_catching_up_rather_than_wait_out_a_long_rate_limit() {
+    let mock = MockStoat::start();
+    let dir = Bot::temp_dir("gives_up_catching_up_rather_than_wait_out_a_long_rate_limit");
+    std::fs::write(dir.join("last-seen.json"), format!(r#"{{"{}":"{}"}}"#, CHANNEL_ID, mock_stoat::new_id())).unwrap();
+    mock.miss_mention("in 1s you missed this");
+    mock.respond_once("GET", &format!("/channels/{}/messages", CHANNEL_ID), 429, r#"{"retry_after":60000}"#);
+    let bot = Bot::start_in(&mock, dir);
+
+    // it gets on with listening instead.
+    assert!(mock_stoat::wait_until(|| bot.log().contains("ran out of time catching up").then_some(())).is_some(), "{}", bot.log());
+    mock.send_mention("license");
+    let posted = mock.wait_for_call(is_post);
+    assert_ne!(mock_stoat::string_field(&posted.body, "content").as_deref(), Some("you missed this"));
+}
+
+#[test]
+fn counts_from_when_a_message_was_sent() {
+    let mock = MockStoat::start();
+    let dir = Bot::temp_dir("counts_from_when_a_message_was_sent");
+    // sent ten minutes ago, while the bot was offline.
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let sent = now - 10 * 60 * 1000;
+    let missed = mock.miss_mention_with_id("in 1h stand up", mock_stoat::id_at(sent));
+    // the bot last saw something just before it.
+    std::fs::write(dir.join("last-seen.json"), format!(r#"{{"{}":"{}"}}"#, CHANNEL_ID, mock_stoat::id_at(sent - 1000))).unwrap();
+    let bot = Bot::start_in(&mock, dir);
+
+    let alarm_path = bot.path(&format!("alarms/{}/{}", CHANNEL_ID, missed));
+    let saved = mock_stoat::wait_until(|| std::fs::read_to_string(&alarm_path).ok()).unwrap_or_else(|| panic!("{}", bot.log()));
+    let when = saved.split(r#""when":"#).nth(1).unwrap().split([',', '}']).next().unwrap();
+    assert_eq!(when.parse::<u64>().unwrap(), sent + 60 * 60 * 1000);
+}
+
+#[test]
+fn quits_when_the_token_is_refused() {
+    let mock = MockStoat::start();
+    mock.respond_once("GET", "/users/@me", 401, r#"{"type":"InvalidSession"}"#);
+    let mut bot = Bot::start(&mock, "quits_when_the_token_is_refused");
+    assert!(mock_stoat::wait_until(|| bot.exit_status()).is_some(), "{}", bot.log());
+    assert!(bot.log().contains("make sure it's set correctly"), "{}", bot.log());
+    assert!(mock.received().is_empty());
+}
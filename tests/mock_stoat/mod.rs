@@ -0,0 +1,539 @@
+// a stand-in for the stoat api and event websocket, for the end to end tests.
+// it listens on localhost over plain http and ws, and the bot is pointed at it
+// with the STOAT_API_URL environment variable.  the api root tells it where the websocket is.
+//
+// every api request the bot makes is recorded, and answered like stoat would
+// (or with a scripted response, to test how the bot copes with errors).
+// the websocket expects an Authenticate, answers with Authenticated and Ready,
+// and then sends whatever events the test has queued up.
+
+use std::collections::VecDeque;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use tungstenite::Message;
+
+// who the bot is told it is by /users/@me.
+pub const BOT_ID: &str = "01J0000000000000000000B0TS";
+pub const SERVER_ID: &str = "01J0000000000000000000SRVR";
+pub const CHANNEL_ID: &str = "01J0000000000000000000CHAN";
+pub const AUTHOR_ID: &str = "01J0000000000000000000ATHR";
+// the bot's dm with the author, which it's told about in Ready.
+pub const DM_CHANNEL_ID: &str = "01J00000000000000000000DMS";
+// another bot in the server, which the bot's told about in Ready.
+pub const OTHER_BOT_ID: &str = "01J000000000000000000THB0T";
+
+#[derive(Clone, Debug)]
+pub struct Call {
+    pub method: String,
+    pub path: String,
+    pub idempotency_key: Option<String>,
+    pub body: String
+}
+
+// what to do instead of the usual, the next time a request matches.
+enum Instead {
+    Respond(u16, String),
+    // handle it as usual, then hang up without answering,
+    // like a connection that broke just after the request got through.
+    HangUp
+}
+
+struct Scripted {
+    method: String,
+    path_prefix: String,
+    instead: Instead
+}
+
+#[derive(Default)]
+struct State {
+    events_url: String,
+    calls: Vec<Call>,
+    scripted: Vec<Scripted>,
+    events: VecDeque<String>,
+    // every message sent in CHANNEL_ID, as (id, json), oldest first.
+    history: Vec<(String, String)>,
+    // frames the bot sent over the websocket.
+    received: Vec<String>,
+    // goes up by one every time the test asks for the websocket to be dropped.
+    disconnects: usize,
+    // stops answering heartbeats, like a connection that's died without closing.
+    ignoring_pings: bool,
+    // every Idempotency-Key a message has been sent with.  like stoat,
+    // a message sent with one that's been used already is refused.
+    idempotency_keys: Vec<String>
+}
+
+pub struct MockStoat {
+    pub api_url: String,
+    state: Arc<Mutex<State>>
+}
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// a ulid for right now, like stoat would make.
+// the bot goes by the time in it, so it has to be a real one.
+pub fn new_id() -> String {
+    id_at(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64)
+}
+
+// the random part of the ulid just counts up instead,
+// so ids made in the same millisecond still go in order.
+pub fn id_at(millis: u64) -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+    let count = NEXT_ID.fetch_add(1, Ordering::Relaxed) as u128;
+    let mut id = String::new();
+    for index in (0..10).rev() {
+        id.push(ALPHABET[((millis >> (index * 5)) & 31) as usize] as char);
+    }
+    for index in (0..16).rev() {
+        id.push(ALPHABET[((count >> (index * 5)) & 31) as usize] as char);
+    }
+    id
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            character if (character as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", character as u32)),
+            character => escaped.push(character)
+        }
+    }
+    escaped
+}
+
+// pulls a string field out of a json object, without a json parser.
+// good enough for the flat bodies the bot sends.
+pub fn string_field(json: &str, name: &str) -> Option<String> {
+    let start = json.find(&format!("\"{}\":\"", name))? + name.len() + 4;
+    let mut value = String::new();
+    let mut characters = json[start..].chars();
+    while let Some(character) = characters.next() {
+        match character {
+            '"' => return Some(value),
+            '\\' => match characters.next()? {
+                'n' => value.push('\n'),
+                other => value.push(other)
+            },
+            character => value.push(character)
+        }
+    }
+    None
+}
+
+impl MockStoat {
+    pub fn start() -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let events_listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let events_url = format!("ws://127.0.0.1:{}/", events_listener.local_addr().unwrap().port());
+        state.lock().unwrap().events_url = events_url.clone();
+
+        let api_listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let api_url = format!("http://127.0.0.1:{}", api_listener.local_addr().unwrap().port());
+        let api_state = state.clone();
+        thread::spawn(move || {
+            for stream in api_listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let state = api_state.clone();
+                thread::spawn(move || serve_api(stream, &state));
+            }
+        });
+
+        let events_state = state.clone();
+        thread::spawn(move || {
+            for stream in events_listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let state = events_state.clone();
+                thread::spawn(move || serve_events(stream, &state));
+            }
+        });
+
+        Self {
+            api_url,
+            state
+        }
+    }
+
+    // queues up an event for the websocket to send once the bot's connected.
+    pub fn send_event(&self, event: String) {
+        self.state.lock().unwrap().events.push_back(event);
+    }
+
+    // a message in CHANNEL_ID from AUTHOR_ID that mentions the bot,
+    // which goes in the channel's history without an event being sent,
+    // like it was sent while the bot wasn't connected.
+    // gives back the message's id.
+    pub fn miss_mention(&self, content: &str) -> String {
+        self.miss_mention_with_id(content, new_id())
+    }
+
+    // for a message that was sent a while ago, with an id from id_at.
+    pub fn miss_mention_with_id(&self, content: &str, id: String) -> String {
+        let message = mention_json(&id, AUTHOR_ID, "", content);
+        self.state.lock().unwrap().history.push((id.clone(), message));
+        id
+    }
+
+    // like miss_mention, but the bot's sent an event about it too.
+    pub fn send_mention(&self, content: &str) -> String {
+        self.send_mention_from(AUTHOR_ID, "", content)
+    }
+
+    // a mention from someone other than AUTHOR_ID, with extra_fields
+    // (like `"masquerade":{"name":"someone"}`) added to the message.
+    pub fn send_mention_from(&self, author: &str, extra_fields: &str, content: &str) -> String {
+        let id = new_id();
+        let message = mention_json(&id, author, extra_fields, content);
+        self.state.lock().unwrap().history.push((id.clone(), message.clone()));
+        self.send_event(message.replacen('{', r#"{"type":"Message","#, 1));
+        id
+    }
+
+    pub fn respond_once(&self, method: &str, path_prefix: &str, status: u16, body: &str) {
+        self.state.lock().unwrap().scripted.push(Scripted {
+            method: method.to_string(),
+            path_prefix: path_prefix.to_string(),
+            instead: Instead::Respond(status, body.to_string())
+        });
+    }
+
+    pub fn hang_up_once(&self, method: &str, path_prefix: &str) {
+        self.state.lock().unwrap().scripted.push(Scripted {
+            method: method.to_string(),
+            path_prefix: path_prefix.to_string(),
+            instead: Instead::HangUp
+        });
+    }
+
+    // closes the websocket connection, like stoat restarting would.
+    pub fn disconnect(&self) {
+        self.state.lock().unwrap().disconnects += 1;
+    }
+
+    pub fn ignore_pings(&self) {
+        self.state.lock().unwrap().ignoring_pings = true;
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    pub fn received(&self) -> Vec<String> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    // waits for the bot to make a call that matches, and gives it back.
+    pub fn wait_for_call(&self, matches: impl Fn(&Call) -> bool) -> Call {
+        wait_until(|| self.calls().into_iter().find(|call| matches(call)))
+            .unwrap_or_else(|| panic!("the bot never made the call, it made these instead: {:#?}", self.calls()))
+    }
+
+    pub fn wait_for_calls(&self, count: usize, matches: impl Fn(&Call) -> bool) -> Vec<Call> {
+        wait_until(|| {
+            let matching: Vec<Call> = self.calls().into_iter().filter(|call| matches(call)).collect();
+            (matching.len() >= count).then_some(matching)
+        }).unwrap_or_else(|| panic!("the bot never made {} of the call, it made these instead: {:#?}", count, self.calls()))
+    }
+}
+
+// polls until check gives something back, or gives up after a while.
+pub fn wait_until<T>(mut check: impl FnMut() -> Option<T>) -> Option<T> {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(30) {
+        if let Some(found) = check() {
+            return Some(found);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    None
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &str) {
+    let head = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nContent-Type: application/json\r\n\r\n", status, body.len());
+    let _ = stream.write_all(head.as_bytes()).and_then(|()| stream.write_all(body.as_bytes()));
+}
+
+fn mention_json(id: &str, author: &str, extra_fields: &str, content: &str) -> String {
+    let extra_fields = if extra_fields.is_empty() { String::new() } else { format!(",{}", extra_fields) };
+    format!(
+        r#"{{"_id":"{}","channel":"{}","author":"{}","content":"{}","mentions":["{}"]{}}}"#,
+        id, CHANNEL_ID, author, escape(content), BOT_ID, extra_fields
+    )
+}
+
+fn message_json(id: &str, channel: &str, content: &str) -> String {
+    format!(r#"{{"_id":"{}","channel":"{}","author":"{}","content":"{}"}}"#, id, channel, BOT_ID, escape(content))
+}
+
+// what stoat would say to a request, going by its path.
+fn usual_response(method: &str, path: &str, body: &str, state: &State) -> (u16, String) {
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let query_value = |name: &str| query.split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+        .unwrap_or_default()
+        .to_string();
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        // january's turned off, so there's a feature for the bot to warn about.
+        ("GET", [""]) => {
+            (200, format!(r#"{{"revolt":"0.8.0","features":{{"email":false,"autumn":{{"enabled":true,"url":""}},"january":{{"enabled":false,"url":""}}}},"ws":"{}"}}"#, state.events_url))
+        },
+        ("GET", ["channels", channel, "messages"]) if *channel == CHANNEL_ID => {
+            // only what the bot asks for when catching up: after=<id>&sort=Oldest&limit=<n>.
+            let after = query_value("after");
+            let limit = query_value("limit").parse().unwrap_or(50);
+            let messages: Vec<&str> = state.history.iter()
+                .filter(|(id, _)| *id > after)
+                .take(limit)
+                .map(|(_, message)| message.as_str())
+                .collect();
+            (200, format!("[{}]", messages.join(",")))
+        },
+        ("POST", ["channels", channel, "messages"]) => {
+            (200, message_json(&new_id(), channel, &string_field(body, "content").unwrap_or_default()))
+        },
+        ("PATCH", ["channels", channel, "messages", message]) => {
+            (200, message_json(message, channel, &string_field(body, "content").unwrap_or_default()))
+        },
+        ("GET", ["channels", channel]) => {
+            (200, format!(r#"{{"_id":"{}","channel_type":"TextChannel","name":"alarms","server":"{}"}}"#, channel, SERVER_ID))
+        },
+        ("GET", ["users", "@me"]) => {
+            (200, format!(r#"{{"_id":"{}","username":"alarm","discriminator":"0000","bot":{{"owner":"{}"}}}}"#, BOT_ID, AUTHOR_ID))
+        },
+        ("GET", ["users", user]) => {
+            (200, format!(r#"{{"_id":"{}","username":"someone","discriminator":"0001"}}"#, user))
+        },
+        ("PUT" | "DELETE", ["channels", _, "messages", _, "reactions", _]) | ("DELETE", ["channels", _, "messages", _]) => {
+            (204, String::new())
+        },
+        _ => (404, r#"{"type":"NotFound"}"#.to_string())
+    }
+}
+
+fn serve_api(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut stream = stream;
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+
+        let mut content_length = 0;
+        let mut idempotency_key = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).unwrap_or(0) == 0 {
+                return;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                } else if name.eq_ignore_ascii_case("idempotency-key") {
+                    idempotency_key = Some(value.trim().to_string());
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+        let body = String::from_utf8_lossy(&body).to_string();
+
+        let (status, response_body, hang_up) = {
+            let mut state = state.lock().unwrap();
+            state.calls.push(Call {
+                method: method.clone(),
+                path: path.clone(),
+                idempotency_key: idempotency_key.clone(),
+                body: body.clone()
+            });
+            let scripted = state.scripted.iter()
+                .position(|scripted| scripted.method == method && path.starts_with(&scripted.path_prefix))
+                .map(|index| state.scripted.remove(index).instead);
+            match scripted {
+                Some(Instead::Respond(status, body)) => (status, body, false),
+                _ if method == "POST" && idempotency_key.as_ref().is_some_and(|key| state.idempotency_keys.contains(key)) => {
+                    (409, r#"{"type":"DuplicateNonce"}"#.to_string(), false)
+                },
+                scripted => {
+                    if let Some(key) = idempotency_key {
+                        state.idempotency_keys.push(key);
+                    }
+                    let (status, body) = usual_response(&method, &path, &body, &state);
+                    (status, body, matches!(scripted, Some(Instead::HangUp)))
+                }
+            }
+        };
+        if hang_up {
+            return;
+        }
+        respond(&mut stream, status, &response_body);
+    }
+}
+
+fn serve_events(stream: TcpStream, state: &Mutex<State>) {
+    let Ok(mut websocket) = tungstenite::accept(stream) else {
+        return;
+    };
+    let disconnects = state.lock().unwrap().disconnects;
+    let Ok(Message::Text(authenticate)) = websocket.read() else {
+        return;
+    };
+    state.lock().unwrap().received.push(authenticate.to_string());
+    if !authenticate.contains(r#""type":"Authenticate""#) {
+        let _ = websocket.send(Message::text(r#"{"type":"Error","error":"InvalidSession"}"#));
+        return;
+    }
+    let _ = websocket.send(Message::text(r#"{"type":"Authenticated"}"#));
+    let ready = format!(
+        r#"{{"type":"Ready","users":[{{"_id":"{}","username":"someone","discriminator":"0001"}},{{"_id":"{}","username":"other","discriminator":"0002","bot":{{"owner":"{}"}}}}],"servers":[{{"_id":"{}","owner":"{}","name":"test server","channels":["{}"]}}],"channels":[{{"_id":"{}","channel_type":"TextChannel","name":"alarms","server":"{}"}},{{"_id":"{}","channel_type":"DirectMessage","recipients":["{}","{}"],"active":true}}],"members":[]}}"#,
+        AUTHOR_ID, OTHER_BOT_ID, AUTHOR_ID, SERVER_ID, AUTHOR_ID, CHANNEL_ID, CHANNEL_ID, SERVER_ID, DM_CHANNEL_ID, BOT_ID, AUTHOR_ID
+    );
+    let _ = websocket.send(Message::text(ready));
+
+    // check for queued events every so often, in between reading what the bot sends.
+    let _ = websocket.get_ref().set_read_timeout(Some(Duration::from_millis(50)));
+    loop {
+        if state.lock().unwrap().disconnects != disconnects {
+            let _ = websocket.close(None);
+            let _ = websocket.flush();
+            return;
+        }
+        let event = state.lock().unwrap().events.pop_front();
+        if let Some(event) = event
+            && websocket.send(Message::text(event)).is_err()
+        {
+            return;
+        }
+        match websocket.read() {
+            Ok(Message::Text(text)) => {
+                let mut state = state.lock().unwrap();
+                state.received.push(text.to_string());
+                // stoat answers {"type":"Ping","data":n} with {"type":"Pong","data":n}.
+                if string_field(&text, "type").as_deref() == Some("Ping") && !state.ignoring_pings {
+                    let pong = text.replace(r#""type":"Ping""#, r#""type":"Pong""#);
+                    if websocket.send(Message::text(pong)).is_err() {
+                        return;
+                    }
+                }
+            },
+            Ok(Message::Close(_)) => return,
+            Ok(_) => {},
+            Err(tungstenite::Error::Io(what_happened)) if matches!(what_happened.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {},
+            Err(_) => return
+        }
+    }
+}
+
+// the real bot, running in its own folder and pointed at the mock server.
+// it's killed when this is dropped.
+pub struct Bot {
+    child: Child,
+    pub dir: PathBuf
+}
+
+impl Bot {
+    pub fn start(mock: &MockStoat, test_name: &str) -> Self {
+        Self::start_in(mock, Self::temp_dir(test_name))
+    }
+
+    // starts the bot in a folder that's already been set up, like with settings.
+    pub fn start_in(mock: &MockStoat, dir: PathBuf) -> Self {
+        let log = std::fs::File::create(dir.join("bot.log")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_thats-quite-alarming"))
+            .current_dir(&dir)
+            .env("STOAT_API_URL", &mock.api_url)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone().unwrap())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+        Self {
+            child,
+            dir
+        }
+    }
+
+    // an empty folder for the bot to keep its files in.
+    pub fn temp_dir(test_name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("thats-quite-alarming-{}-{}", test_name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    pub fn path(&self, relative: &str) -> PathBuf {
+        self.dir.join(relative)
+    }
+
+    // runs the bot's own command line, like "export", in the same folder, and waits for it.
+    pub fn run(&self, args: &[&str]) -> std::process::Output {
+        Command::new(env!("CARGO_BIN_EXE_thats-quite-alarming"))
+            .current_dir(&self.dir)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap()
+    }
+
+    pub fn stop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+
+    // None while it's still running.
+    pub fn exit_status(&mut self) -> Option<std::process::ExitStatus> {
+        self.child.try_wait().unwrap()
+    }
+
+    pub fn log(&self) -> String {
+        std::fs::read_to_string(self.dir.join("bot.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for Bot {
+    fn drop(&mut self) {
+        self.stop();
+        if !thread::panicking() {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+}
+
+pub fn exists(path: &Path) -> bool {
+    std::fs::exists(path).unwrap_or(false)
+}